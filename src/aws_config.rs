@@ -7,6 +7,9 @@ use std::collections::HashMap;
 use std::result;
 use std::str::FromStr;
 use rusoto_core::Region;
+use std::fmt;
+use config::redact;
+use tags;
 
 pub trait LoadFromPath where Self: Sized {
     type Error: Sized + 'static;
@@ -32,12 +35,35 @@ impl LoadFromPath for Ini {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct ConfigProfile {
     pub name: String,
     pub role_arn: Option<String>,
     pub source_profile: Option<String>,
     pub region: Option<Region>,
+    pub mfa_serial: Option<String>,
+    pub external_id: Option<String>,
+    pub duration_seconds: Option<i64>,
+    pub role_session_name: Option<String>,
+    pub session_tags: tags::SessionTags,
+    pub web_identity_token_file: Option<String>,
+}
+
+impl fmt::Debug for ConfigProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ConfigProfile")
+            .field("name", &self.name)
+            .field("role_arn", &self.role_arn)
+            .field("source_profile", &self.source_profile)
+            .field("region", &self.region)
+            .field("mfa_serial", &self.mfa_serial)
+            .field("external_id", &redact(&self.external_id))
+            .field("duration_seconds", &self.duration_seconds)
+            .field("role_session_name", &self.role_session_name)
+            .field("session_tags", &self.session_tags)
+            .field("web_identity_token_file", &self.web_identity_token_file)
+            .finish()
+    }
 }
 
 impl ConfigProfile {
@@ -47,13 +73,30 @@ impl ConfigProfile {
             role_arn: None,
             source_profile: None,
             region: None,
+            mfa_serial: None,
+            external_id: None,
+            duration_seconds: None,
+            role_session_name: None,
+            session_tags: tags::SessionTags::default(),
+            web_identity_token_file: None,
         }
     }
 }
 
+/// The roles to assume, in order, to get credentials for a profile.
+#[derive(Debug, Clone)]
+pub struct RoleChain {
+    /// The profile in the credentials file holding the long term keys used for the first hop.
+    pub credentials_profile: String,
+    /// Profiles with a `role_arn`, starting with the one assumed using the long term keys.
+    pub roles: Vec<ConfigProfile>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub default_region: Option<Region>,
+    /// Whether there is a `default` section, which isn't in `profiles`.
+    pub has_default: bool,
     pub profiles: HashMap<String, ConfigProfile>,
 }
 
@@ -61,6 +104,44 @@ impl Config {
     pub fn load_from_path(path: &Path) -> Result<Config> {
         parse_config_file(path)
     }
+
+    /// Follows the `source_profile` links from a profile back to a profile without a `role_arn`.
+    ///
+    /// A profile whose `source_profile` names itself takes its keys from the credentials file.
+    pub fn resolve_role_chain(&self, profile: &str) -> Result<RoleChain> {
+        let mut roles = vec![];
+        let mut visited: Vec<String> = vec![];
+        let mut name = profile.to_owned();
+
+        loop {
+            if visited.contains(&name) {
+                visited.push(name);
+                return Err(StsCliError::SourceProfileLoop(visited.join(" -> ")));
+            }
+
+            visited.push(name.clone());
+
+            match self.profiles.get(&name) {
+                Some(profile_config) if profile_config.role_arn.is_some() => {
+                    roles.push(profile_config.clone());
+
+                    let source_profile = profile_config.source_profile.clone().unwrap_or("default".to_owned());
+                    if source_profile == name {
+                        break;
+                    }
+                    name = source_profile;
+                },
+                _ => break,
+            }
+        }
+
+        roles.reverse();
+
+        Ok(RoleChain {
+            credentials_profile: name,
+            roles: roles,
+        })
+    }
 }
 
 fn get_profile_name_from_section_name(section_name: &str) -> Option<String> {
@@ -76,7 +157,7 @@ fn get_profile_name_from_section_name(section_name: &str) -> Option<String> {
 
 
 fn parse_config_file(file_path: &Path) -> Result<Config> {
-    let ini = try!(Ini::load_from_path(file_path).into());
+    let ini = try!(Ini::load_from_path(file_path));
 
     let default_section = ini.section(Some("default".to_owned()));
     let maybe_default_region_name = default_section.and_then(|s| s.get("region"));
@@ -101,12 +182,34 @@ fn parse_config_file(file_path: &Path) -> Result<Config> {
                 };
                 let source_profile = section.get("source_profile").map(|s| s.to_owned());
                 let role_arn = section.get("role_arn").map(|s| s.to_owned());
+                let mfa_serial = section.get("mfa_serial").map(|s| s.to_owned());
+                let external_id = section.get("external_id").map(|s| s.to_owned());
+                let role_session_name = section.get("role_session_name").map(|s| s.to_owned());
+                let web_identity_token_file = section.get("web_identity_token_file").map(|s| s.to_owned());
+                let duration_seconds = if let Some(duration) = section.get("duration_seconds") {
+                    Some(try!(duration.parse::<i64>()
+                        .map_err(|e| StsCliError::Error(format!("invalid duration_seconds for profile {}: {}", profile_name, e)))))
+                } else {
+                    None
+                };
+
+                let session_tags = try!(tags::SessionTags::new(
+                    try!(tags::parse_tag_list(section.get("session_tags").map(|s| &s[..]).unwrap_or(""))),
+                    tags::parse_key_list(section.get("transitive_tag_keys").map(|s| &s[..]).unwrap_or("")),
+                    section.get("source_identity").map(|s| s.to_owned()))
+                    .map_err(|e| e.with_context(format!("invalid session tags for profile {}", profile_name))));
 
                 profiles.insert(profile_name.clone(), ConfigProfile {
                     name: profile_name,
                     role_arn: role_arn,
                     source_profile: source_profile,
                     region: region,
+                    mfa_serial: mfa_serial,
+                    external_id: external_id,
+                    duration_seconds: duration_seconds,
+                    role_session_name: role_session_name,
+                    session_tags: session_tags,
+                    web_identity_token_file: web_identity_token_file,
                 });
             }
         }
@@ -114,6 +217,139 @@ fn parse_config_file(file_path: &Path) -> Result<Config> {
 
     Ok(Config {
         default_region: default_region,
+        has_default: default_section.is_some(),
         profiles: profiles,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use files;
+    use super::*;
+
+    fn parse(name: &str, contents: &str) -> Result<Config> {
+        let dir = env::temp_dir().join(format!("stscli-aws-config-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        files::write_private_file_atomic(&path, contents.as_bytes()).unwrap();
+        Config::load_from_path(&path)
+    }
+
+    #[test]
+    fn debug_output_hides_the_external_id() {
+        let mut profile = ConfigProfile::new("partner");
+        profile.external_id = Some("s3cr3t-external-id".to_owned());
+
+        let debug = format!("{:?}", profile);
+        assert!(!debug.contains("s3cr3t-external-id"), "{}", debug);
+        assert!(debug.contains("external_id: Some(\"<redacted>\")"), "{}", debug);
+
+        assert!(format!("{:?}", ConfigProfile::new("partner")).contains("external_id: None"));
+    }
+
+    const CHAIN: &'static str = "
+[default]
+region = eu-west-1
+
+[profile hub]
+role_arn = arn:aws:iam::111111111111:role/hub
+mfa_serial = arn:aws:iam::111111111111:mfa/user
+
+[profile prod]
+role_arn = arn:aws:iam::222222222222:role/prod
+source_profile = hub
+region = eu-central-1
+duration_seconds = 3600
+session_tags = team=infra, project=billing
+transitive_tag_keys = team
+
+[profile self]
+role_arn = arn:aws:iam::333333333333:role/self
+source_profile = self
+
+[profile keys]
+region = us-west-2
+";
+
+    fn role_names(chain: &RoleChain) -> Vec<&str> {
+        chain.roles.iter().map(|p| &p.name[..]).collect()
+    }
+
+    #[test]
+    fn parses_profiles() {
+        let config = parse("parse", CHAIN).unwrap();
+
+        assert_eq!(config.default_region, Some(Region::EuWest1));
+        assert!(config.has_default);
+        assert!(!config.profiles.contains_key("default"));
+
+        let prod = &config.profiles["prod"];
+        assert_eq!(prod.role_arn, Some("arn:aws:iam::222222222222:role/prod".to_owned()));
+        assert_eq!(prod.source_profile, Some("hub".to_owned()));
+        assert_eq!(prod.region, Some(Region::EuCentral1));
+        assert_eq!(prod.duration_seconds, Some(3600));
+        assert_eq!(prod.session_tags.tags, vec![("team".to_owned(), "infra".to_owned()), ("project".to_owned(), "billing".to_owned())]);
+        assert_eq!(prod.session_tags.transitive_tag_keys, vec!["team".to_owned()]);
+
+        assert_eq!(config.profiles["hub"].mfa_serial, Some("arn:aws:iam::111111111111:mfa/user".to_owned()));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(parse("duration", "[profile a]\nduration_seconds = 1h\n").is_err());
+        assert!(parse("region", "[profile a]\nregion = mars-north-1\n").is_err());
+        assert!(parse("tags", "[profile a]\nsession_tags = team\n").is_err());
+        assert!(!parse("no-default", "[profile a]\n").unwrap().has_default);
+    }
+
+    #[test]
+    fn resolves_role_chains() {
+        let config = parse("chain", CHAIN).unwrap();
+
+        let prod = config.resolve_role_chain("prod").unwrap();
+        assert_eq!(role_names(&prod), vec!["hub", "prod"]);
+        assert_eq!(prod.credentials_profile, "default");
+
+        let keys = config.resolve_role_chain("keys").unwrap();
+        assert!(keys.roles.is_empty());
+        assert_eq!(keys.credentials_profile, "keys");
+
+        let unknown = config.resolve_role_chain("unknown").unwrap();
+        assert!(unknown.roles.is_empty());
+        assert_eq!(unknown.credentials_profile, "unknown");
+    }
+
+    #[test]
+    fn a_profile_can_be_its_own_source() {
+        let chain = parse("self", CHAIN).unwrap().resolve_role_chain("self").unwrap();
+
+        assert_eq!(role_names(&chain), vec!["self"]);
+        assert_eq!(chain.credentials_profile, "self");
+    }
+
+    #[test]
+    fn detects_source_profile_loops() {
+        let config = parse("loop", "
+[profile a]
+role_arn = arn:aws:iam::111111111111:role/a
+source_profile = b
+
+[profile b]
+role_arn = arn:aws:iam::111111111111:role/b
+source_profile = c
+
+[profile c]
+role_arn = arn:aws:iam::111111111111:role/c
+source_profile = a
+").unwrap();
+
+        match config.resolve_role_chain("a") {
+            Err(StsCliError::SourceProfileLoop(chain)) => assert_eq!(chain, "a -> b -> c -> a"),
+            other => panic!("expected a loop, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,241 @@
+use chrono::{DateTime, Duration, Utc};
+use regex::Regex;
+use rusoto_core::AwsCredentials;
+use serde_json;
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::PathBuf;
+use files;
+use session::Session;
+use result::*;
+
+pub const DEFAULT_CACHE_MARGIN_SECONDS: i64 = 300;
+
+/// Identifies a set of cached credentials.
+///
+/// Two invocations share cached credentials only if they resolve to the same profile, role,
+/// session name, MFA device, region and source credentials, and ask for the same session options.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheKey {
+    pub profile: String,
+    pub role_arn: Option<String>,
+    pub role_session_name: String,
+    pub serial_number: Option<String>,
+    #[serde(default)]
+    pub region: String,
+    /// Where the credentials the session is made from come from, e.g. a profile in a credentials file.
+    #[serde(default)]
+    pub source: String,
+    /// Request parameters that change what the session can do, such as session policies.
+    #[serde(default)]
+    pub session_params: BTreeMap<String, String>,
+}
+
+impl CacheKey {
+    fn file_name(&self) -> String {
+        let re = Regex::new(r"[^A-Za-z0-9_.@=,+-]").unwrap();
+
+        let parts = [
+            Some(&self.profile),
+            self.role_arn.as_ref(),
+            Some(&self.role_session_name),
+            self.serial_number.as_ref(),
+            Some(&self.region),
+        ];
+
+        let mut name: Vec<String> = parts.iter()
+            .map(|part| part.map(|s| re.replace_all(s, "_")).unwrap_or("".to_owned()))
+            .collect();
+
+        // the source and parameters can be long, so just tell entries apart by a hash. The entry records the full key
+        let mut hasher = DefaultHasher::new();
+        self.source.hash(&mut hasher);
+        self.session_params.hash(&mut hasher);
+        name.push(format!("{:016x}", hasher.finish()));
+
+        format!("{}.json", name.join("--"))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    key: CacheKey,
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+    expiration: String,
+    #[serde(default)]
+    assumed_role_arn: Option<String>,
+}
+
+/// Stores temporary credentials on disk so repeated invocations don't call STS every time.
+#[derive(Debug, Clone)]
+pub struct CredentialsCache {
+    dir: PathBuf,
+    margin: Duration,
+}
+
+impl CredentialsCache {
+    pub fn new(dir: PathBuf, margin_seconds: i64) -> CredentialsCache {
+        CredentialsCache {
+            dir: dir,
+            margin: Duration::seconds(margin_seconds),
+        }
+    }
+
+    pub fn default_location() -> Result<PathBuf> {
+        match env::home_dir() {
+            Some(home_path) => Ok(home_path.join(".aws").join("stscli").join("cache")),
+            None => Err(StsCliError::Error("The environment variable HOME must be set.".to_owned())),
+        }
+    }
+
+    /// Returns the cached credentials for the key if they are still valid for longer than the margin.
+    pub fn get(&self, key: &CacheKey) -> Result<Option<Session>> {
+        let path = self.dir.join(key.file_name());
+
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(StsCliError::from(err)),
+        };
+
+        let mut contents = String::new();
+        try!(file.read_to_string(&mut contents));
+
+        let entry: CacheEntry = try!(serde_json::from_str(&contents));
+
+        if entry.key != *key {
+            debug!("cache entry {:?} belongs to a different key", path);
+            return Ok(None);
+        }
+
+        let expires_at = try!(entry.expiration.parse::<DateTime<Utc>>()
+            .map_err(|e| StsCliError::Error(format!("invalid expiration in {:?}: {}", path, e))));
+
+        if expires_at <= Utc::now() + self.margin {
+            debug!("cache entry {:?} expires at {}", path, expires_at);
+            return Ok(None);
+        }
+
+        Ok(Some(Session {
+            credentials: AwsCredentials::new(entry.access_key_id, entry.secret_access_key, entry.session_token, expires_at),
+            role_arn: key.role_arn.clone(),
+            assumed_role_arn: entry.assumed_role_arn,
+        }))
+    }
+
+    pub fn put(&self, key: &CacheKey, session: &Session) -> Result<()> {
+        let creds = &session.credentials;
+
+        let entry = CacheEntry {
+            key: key.clone(),
+            access_key_id: creds.aws_access_key_id().to_owned(),
+            secret_access_key: creds.aws_secret_access_key().to_owned(),
+            session_token: creds.token().clone(),
+            expiration: creds.expires_at().to_rfc3339(),
+            assumed_role_arn: session.assumed_role_arn.clone(),
+        };
+
+        let contents = try!(serde_json::to_string(&entry));
+
+        try!(files::create_private_dir_all(&self.dir));
+        files::write_private_file_atomic(&self.dir.join(key.file_name()), contents.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use rusoto_core::AwsCredentials;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use session::Session;
+    use super::*;
+
+    fn key() -> CacheKey {
+        CacheKey {
+            profile: "dev".to_owned(),
+            role_arn: Some("arn:aws:iam::123456789012:role/admin".to_owned()),
+            role_session_name: "stscli".to_owned(),
+            serial_number: None,
+            region: "us-east-1".to_owned(),
+            source: "profile:dev".to_owned(),
+            session_params: BTreeMap::new(),
+        }
+    }
+
+    fn session(expires_in: Duration) -> Session {
+        Session::new(AwsCredentials::new("AKID", "SECRET", Some("TOKEN".to_owned()), Utc::now() + expires_in))
+    }
+
+    fn cache(name: &str) -> CredentialsCache {
+        let dir = env::temp_dir().join(format!("stscli-cache-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+
+        CredentialsCache::new(dir, DEFAULT_CACHE_MARGIN_SECONDS)
+    }
+
+    #[test]
+    fn file_names_differ_by_region_and_source() {
+        let mut other_region = key();
+        other_region.region = "eu-west-1".to_owned();
+
+        let mut other_source = key();
+        other_source.source = "environment:AKIDEXAMPLE".to_owned();
+
+        assert!(key().file_name() != other_region.file_name());
+        assert!(key().file_name() != other_source.file_name());
+        assert!(other_region.file_name() != other_source.file_name());
+    }
+
+    #[test]
+    fn file_names_are_safe() {
+        let mut key = key();
+        key.profile = "../../etc/passwd".to_owned();
+
+        assert!(!key.file_name().contains('/'));
+    }
+
+    #[test]
+    fn returns_what_was_put() {
+        let cache = cache("put");
+
+        cache.put(&key(), &session(Duration::hours(1))).unwrap();
+
+        let cached = cache.get(&key()).unwrap().expect("cached session");
+        assert_eq!(cached.credentials.aws_access_key_id(), "AKID");
+        assert_eq!(cached.role_arn, key().role_arn);
+
+        let _ = fs::remove_dir_all(&cache.dir);
+    }
+
+    #[test]
+    fn ignores_credentials_expiring_within_the_margin() {
+        let cache = cache("margin");
+
+        cache.put(&key(), &session(Duration::seconds(DEFAULT_CACHE_MARGIN_SECONDS - 10))).unwrap();
+
+        assert!(cache.get(&key()).unwrap().is_none());
+
+        let _ = fs::remove_dir_all(&cache.dir);
+    }
+
+    #[test]
+    fn misses_for_other_keys() {
+        let cache = cache("miss");
+
+        cache.put(&key(), &session(Duration::hours(1))).unwrap();
+
+        let mut other = key();
+        other.source = "profile:prod".to_owned();
+        assert!(cache.get(&other).unwrap().is_none());
+
+        let _ = fs::remove_dir_all(&cache.dir);
+    }
+}
@@ -1,11 +1,17 @@
 use rusoto_core;
 use clap::ArgMatches;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::env;
+use std::fmt;
 use result::*;
+use cache;
+use mfa;
+use duration;
+use policy;
+use tags;
 
-#[derive(Debug, Clone)]
+#[derive(Clone, Default)]
 pub struct Config {
     pub config_file: Option<PathBuf>,
     pub credentials_file: Option<PathBuf>,
@@ -15,6 +21,56 @@ pub struct Config {
     pub name: Option<String>,
     pub serial_number: Option<String>,
     pub token_code: Option<String>,
+    pub external_id: Option<String>,
+    pub token_code_command: Option<String>,
+    pub duration: Option<i64>,
+    pub session_policy: policy::SessionPolicy,
+    pub session_tags: tags::SessionTags,
+    pub web_identity: bool,
+    pub web_identity_token_file: Option<PathBuf>,
+    pub saml: bool,
+    pub saml_assertion_file: Option<PathBuf>,
+    pub no_cache: bool,
+    pub refresh: bool,
+    pub cache_margin: i64,
+    /// The credentials are served and refreshed in the background, by `serve`, `imds` or `exec --serve`.
+    pub serve: bool,
+    /// Whether stscli may ask for MFA codes. Off when refreshing served credentials and for `whoami --profile all`.
+    pub interactive: bool,
+}
+
+/// Hides a secret value when printing debug output, while still showing whether it was set.
+pub fn redact(value: &Option<String>) -> Option<&'static str> {
+    value.as_ref().map(|_| "<redacted>")
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("config_file", &self.config_file)
+            .field("credentials_file", &self.credentials_file)
+            .field("profile", &self.profile)
+            .field("role", &self.role)
+            .field("region", &self.region)
+            .field("name", &self.name)
+            .field("serial_number", &self.serial_number)
+            .field("token_code", &redact(&self.token_code))
+            .field("external_id", &redact(&self.external_id))
+            .field("token_code_command", &self.token_code_command)
+            .field("duration", &self.duration)
+            .field("session_policy", &self.session_policy)
+            .field("session_tags", &self.session_tags)
+            .field("web_identity", &self.web_identity)
+            .field("web_identity_token_file", &self.web_identity_token_file)
+            .field("saml", &self.saml)
+            .field("saml_assertion_file", &self.saml_assertion_file)
+            .field("no_cache", &self.no_cache)
+            .field("refresh", &self.refresh)
+            .field("cache_margin", &self.cache_margin)
+            .field("serve", &self.serve)
+            .field("interactive", &self.interactive)
+            .finish()
+    }
 }
 
 impl Config {
@@ -25,17 +81,83 @@ impl Config {
             None
         };
 
-        let default_config_location = Some(try!(Config::default_config_location()));
+        if let Some(token_code) = args.value_of("token_code") {
+            try!(mfa::validate_token_code(token_code));
+        }
+
+        let duration = if let Some(duration) = args.value_of("duration") {
+            Some(try!(duration::parse_duration(duration)))
+        } else {
+            None
+        };
+
+        let policy_arns = args.values_of("policy_arn")
+            .map(|values| values.map(|s| s.to_owned()).collect())
+            .unwrap_or(vec![]);
+        let policy_file = args.value_of("policy_file");
+        if policy_file == Some("-") && subcommand_reads_stdin(args) {
+            return Err(StsCliError::Error(format!("--policy-file - can't be used with {}, which reads its input from stdin",
+                args.subcommand_name().unwrap_or(""))));
+        }
+        let session_policy = try!(policy::SessionPolicy::load(policy_file.map(Path::new), policy_arns));
+
+        let mut tags = vec![];
+        for tag in args.values_of("tag").map(|values| values.collect()).unwrap_or(vec![]) {
+            tags.push(try!(tags::parse_tag(tag)));
+        }
+
+        // checked once merged with the tags from the profile, which transitive keys may refer to
+        let session_tags = tags::SessionTags {
+            tags: tags,
+            transitive_tag_keys: args.values_of("transitive_tag")
+                .map(|values| values.map(|s| s.to_owned()).collect())
+                .unwrap_or(vec![]),
+            source_identity: args.value_of("source_identity").map(|s| s.to_owned()),
+        };
+
+        let cache_margin = if let Some(margin) = args.value_of("cache_margin") {
+            try!(margin.parse::<i64>()
+                .map_err(|e| StsCliError::Error(format!("invalid cache margin {}: {}", margin, e))))
+        } else {
+            cache::DEFAULT_CACHE_MARGIN_SECONDS
+        };
+
+        let serve = match args.subcommand() {
+            ("serve", _) | ("imds", _) => true,
+            (_, Some(sub_matches)) => sub_matches.is_present("serve"),
+            _ => false,
+        };
+
+        let config_file = match args.value_of("config") {
+            Some(config_file) => PathBuf::from(config_file),
+            None => try!(Config::default_config_location()),
+        };
 
         Ok(Config {
-            config_file: args.value_of("config").map(|s| PathBuf::from(s)).or_else(|| default_config_location),
-            credentials_file: args.value_of("credentials").map(|s| PathBuf::from(s)),
+            config_file: Some(config_file),
+            credentials_file: args.value_of("credentials").map(PathBuf::from),
             profile: args.value_of("profile").map(|s| s.to_owned()),
             role: args.value_of("role").map(|s| s.to_owned()),
             region: region,
             name: args.value_of("name").map(|s| s.to_owned()),
             serial_number: args.value_of("serial_number").map(|s| s.to_owned()),
             token_code: args.value_of("token_code").map(|s| s.to_owned()),
+            external_id: args.value_of("external_id").map(|s| s.to_owned()),
+            token_code_command: args.value_of("token_code_command").map(|s| s.to_owned()),
+            duration: duration,
+            session_policy: session_policy,
+            session_tags: session_tags,
+            web_identity: args.subcommand_name() == Some("assume-web-identity"),
+            web_identity_token_file: args.value_of("web_identity_token_file").map(PathBuf::from),
+            saml: args.subcommand_name() == Some("assume-saml"),
+            saml_assertion_file: args.subcommand_matches("assume-saml")
+                .and_then(|sub| sub.value_of("assertion"))
+                .map(PathBuf::from),
+            no_cache: args.is_present("no_cache"),
+            refresh: args.is_present("refresh"),
+            cache_margin: cache_margin,
+            serve: serve,
+            interactive: true,
         })
     }
 
@@ -52,3 +174,67 @@ impl Config {
         }
     }
 }
+
+/// Whether the subcommand reads its input from stdin, which `--policy-file -` would already have taken.
+fn subcommand_reads_stdin(args: &ArgMatches) -> bool {
+    let input = match args.subcommand() {
+        ("assume-saml", Some(sub)) => sub.value_of("assertion"),
+        ("decode-message", Some(sub)) => sub.value_of("message"),
+        _ => return false,
+    };
+
+    input.map(|value| value == "-").unwrap_or(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{App, Arg, SubCommand};
+    use super::*;
+
+    fn matches(args: &[&str]) -> ArgMatches<'static> {
+        App::new("stscli")
+            .arg(Arg::with_name("policy_file").long("policy-file").takes_value(true))
+            .subcommand(SubCommand::with_name("get"))
+            .subcommand(SubCommand::with_name("assume-saml").arg(Arg::with_name("assertion").index(1)))
+            .subcommand(SubCommand::with_name("decode-message").arg(Arg::with_name("message").index(1)))
+            .get_matches_from(args)
+    }
+
+    #[test]
+    fn debug_output_hides_secrets() {
+        let config = Config {
+            token_code: Some("123456".to_owned()),
+            external_id: Some("s3cr3t-external-id".to_owned()),
+            serial_number: Some("arn:aws:iam::111111111111:mfa/user".to_owned()),
+            ..Default::default()
+        };
+
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("123456"), "{}", debug);
+        assert!(!debug.contains("s3cr3t-external-id"), "{}", debug);
+        assert!(debug.contains("token_code: Some(\"<redacted>\")"), "{}", debug);
+        assert!(debug.contains("external_id: Some(\"<redacted>\")"), "{}", debug);
+        assert!(debug.contains("arn:aws:iam::111111111111:mfa/user"), "{}", debug);
+
+        assert!(format!("{:?}", Config::default()).contains("token_code: None"));
+    }
+
+    #[test]
+    fn knows_which_subcommands_read_stdin() {
+        assert!(subcommand_reads_stdin(&matches(&["stscli", "assume-saml"])));
+        assert!(subcommand_reads_stdin(&matches(&["stscli", "assume-saml", "-"])));
+        assert!(!subcommand_reads_stdin(&matches(&["stscli", "assume-saml", "assertion.txt"])));
+        assert!(subcommand_reads_stdin(&matches(&["stscli", "decode-message"])));
+        assert!(!subcommand_reads_stdin(&matches(&["stscli", "decode-message", "message"])));
+        assert!(!subcommand_reads_stdin(&matches(&["stscli", "get"])));
+    }
+
+    #[test]
+    fn policies_from_stdin_cant_be_used_with_subcommands_reading_it() {
+        let err = Config::new_for_matches(&matches(&["stscli", "--policy-file", "-", "decode-message"])).unwrap_err();
+        assert!(err.to_string().contains("decode-message"), "{}", err);
+
+        let err = Config::new_for_matches(&matches(&["stscli", "--policy-file", "-", "assume-saml"])).unwrap_err();
+        assert!(err.to_string().contains("assume-saml"), "{}", err);
+    }
+}
@@ -0,0 +1,274 @@
+use chrono::{DateTime, Utc};
+use rusoto_core::AwsCredentials;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+use files;
+use result::*;
+
+/// Marks sections written by stscli, and says when their credentials expire.
+pub const EXPIRATION_KEY: &'static str = "stscli_expiration";
+
+const ACCESS_KEY_ID_KEY: &'static str = "aws_access_key_id";
+const SECRET_ACCESS_KEY_KEY: &'static str = "aws_secret_access_key";
+const SESSION_TOKEN_KEY: &'static str = "aws_session_token";
+
+/// The keys stscli writes. Other keys in a section, such as `region`, are left alone.
+const MANAGED_KEYS: [&'static str; 4] = [ACCESS_KEY_ID_KEY, SECRET_ACCESS_KEY_KEY, SESSION_TOKEN_KEY, EXPIRATION_KEY];
+
+/// The shared credentials file, edited line by line so comments, other sections and key order survive.
+#[derive(Debug, Clone)]
+pub struct CredentialsFile {
+    lines: Vec<String>,
+    line_ending: &'static str,
+}
+
+enum Line<'a> {
+    Section(&'a str),
+    Entry(&'a str, &'a str),
+    Other,
+}
+
+fn parse_line<'a>(line: &'a str) -> Line<'a> {
+    let trimmed = line.trim();
+
+    if trimmed.starts_with('[') && trimmed.ends_with(']') {
+        Line::Section(trimmed[1..trimmed.len() - 1].trim())
+    } else if trimmed.starts_with('#') || trimmed.starts_with(';') {
+        Line::Other
+    } else if let Some(i) = trimmed.find('=') {
+        Line::Entry(trimmed[..i].trim(), trimmed[i + 1..].trim())
+    } else {
+        Line::Other
+    }
+}
+
+impl CredentialsFile {
+    /// Reads the file, treating a missing file as empty.
+    pub fn load(path: &Path) -> Result<CredentialsFile> {
+        let mut contents = String::new();
+
+        match File::open(path) {
+            Ok(mut file) => { try!(file.read_to_string(&mut contents)); },
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {},
+            Err(err) => return Err(StsCliError::from(err)),
+        }
+
+        Ok(CredentialsFile::parse(&contents))
+    }
+
+    pub fn parse(contents: &str) -> CredentialsFile {
+        CredentialsFile {
+            lines: contents.lines().map(|l| l.to_owned()).collect(),
+            line_ending: if contents.contains("\r\n") { "\r\n" } else { "\n" },
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            try!(files::create_private_dir_all(dir));
+        }
+
+        files::write_private_file_atomic(path, self.contents().as_bytes())
+    }
+
+    pub fn contents(&self) -> String {
+        let mut contents = self.lines.join(self.line_ending);
+
+        if !contents.is_empty() {
+            contents.push_str(self.line_ending);
+        }
+
+        contents
+    }
+
+    /// Inserts or updates a section with temporary credentials.
+    ///
+    /// Refuses to overwrite a section that has credentials stscli didn't write, since
+    /// they are probably long term keys, possibly the ones used to get these credentials.
+    #[allow(clippy::match_like_matches_macro)] // matches! is newer than the Rust this crate is written for
+    pub fn set_credentials(&mut self, section: &str, creds: &AwsCredentials) -> Result<()> {
+        let mut values = vec![
+            (ACCESS_KEY_ID_KEY, creds.aws_access_key_id().to_owned()),
+            (SECRET_ACCESS_KEY_KEY, creds.aws_secret_access_key().to_owned()),
+        ];
+        if let Some(ref token) = *creds.token() {
+            values.push((SESSION_TOKEN_KEY, token.to_owned()));
+        }
+        values.push((EXPIRATION_KEY, creds.expires_at().to_rfc3339()));
+
+        let (start, end) = match self.find_section(section) {
+            Some(range) => range,
+            None => {
+                if self.lines.last().map(|l| !l.trim().is_empty()).unwrap_or(false) {
+                    self.lines.push("".to_owned());
+                }
+                self.lines.push(format!("[{}]", section));
+                (self.lines.len() - 1, self.lines.len())
+            }
+        };
+
+        let keys: Vec<String> = self.lines[start + 1..end].iter()
+            .filter_map(|l| match parse_line(l) { Line::Entry(k, _) => Some(k.to_owned()), _ => None })
+            .collect();
+
+        if keys.iter().any(|k| k == ACCESS_KEY_ID_KEY) && !keys.iter().any(|k| k == EXPIRATION_KEY) {
+            return Err(StsCliError::Error(format!(
+                "profile {} in the credentials file has credentials that stscli didn't write, so they won't be overwritten", section)));
+        }
+
+        let mut section_lines = vec![self.lines[start].clone()];
+        let mut written = vec![];
+
+        for line in &self.lines[start + 1..end] {
+            match parse_line(line) {
+                Line::Entry(key, _) if MANAGED_KEYS.contains(&key) => {
+                    if let Some(&(key, ref value)) = values.iter().find(|&&(k, _)| k == key) {
+                        section_lines.push(format!("{} = {}", key, value));
+                        written.push(key);
+                    }
+                },
+                _ => section_lines.push(line.clone()),
+            }
+        }
+
+        // new keys go after the last entry, so blank lines and comments before the next section stay there
+        let mut insert_at = section_lines.iter()
+            .rposition(|l| match parse_line(l) { Line::Section(_) | Line::Entry(_, _) => true, _ => false })
+            .map(|i| i + 1)
+            .unwrap_or(section_lines.len());
+
+        for &(key, ref value) in &values {
+            if !written.contains(&key) {
+                section_lines.insert(insert_at, format!("{} = {}", key, value));
+                insert_at += 1;
+            }
+        }
+
+        self.lines.splice(start..end, section_lines);
+
+        Ok(())
+    }
+
+    /// Removes sections written by stscli whose credentials have expired, returning their names.
+    pub fn prune_expired(&mut self, now: DateTime<Utc>) -> Vec<String> {
+        let mut pruned = vec![];
+        let mut i = 0;
+
+        while i < self.lines.len() {
+            let name = match parse_line(&self.lines[i]) {
+                Line::Section(name) => name.to_owned(),
+                _ => { i += 1; continue; }
+            };
+
+            let (start, end) = self.find_section_at(i);
+
+            let expired = self.lines[start + 1..end].iter().any(|l| match parse_line(l) {
+                Line::Entry(key, value) if key == EXPIRATION_KEY => {
+                    value.parse::<DateTime<Utc>>().map(|expires_at| expires_at <= now).unwrap_or(false)
+                },
+                _ => false,
+            });
+
+            if expired {
+                // keep comments just before the next section, which are more likely about that one
+                let mut remove_end = end;
+                while remove_end > start + 1 && self.lines[remove_end - 1].trim().starts_with(&['#', ';'][..]) {
+                    remove_end -= 1;
+                }
+
+                self.lines.drain(start..remove_end);
+                pruned.push(name);
+                i = start;
+            } else {
+                i = end;
+            }
+        }
+
+        pruned
+    }
+
+    fn find_section(&self, section: &str) -> Option<(usize, usize)> {
+        self.lines.iter()
+            .position(|l| match parse_line(l) { Line::Section(name) => name == section, _ => false })
+            .map(|start| self.find_section_at(start))
+    }
+
+    /// The range of lines from a section header up to the next header.
+    #[allow(clippy::match_like_matches_macro)] // matches! is newer than the Rust this crate is written for
+    fn find_section_at(&self, start: usize) -> (usize, usize) {
+        let end = self.lines[start + 1..].iter()
+            .position(|l| match parse_line(l) { Line::Section(_) => true, _ => false })
+            .map(|i| start + 1 + i)
+            .unwrap_or(self.lines.len());
+
+        (start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+    use rusoto_core::AwsCredentials;
+    use super::*;
+
+    fn creds(access_key_id: &str, expires_at: &str) -> AwsCredentials {
+        AwsCredentials::new(access_key_id, "SECRET", Some("TOKEN".to_owned()), expires_at.parse().unwrap())
+    }
+
+    #[test]
+    fn appends_a_new_section_keeping_the_rest() {
+        let mut file = CredentialsFile::parse("# my keys\n[default]\naws_access_key_id = AKIDLONGTERM\naws_secret_access_key = S\n");
+
+        file.set_credentials("dev", &creds("ASIA1", "2030-01-01T00:00:00Z")).unwrap();
+
+        assert_eq!(file.contents(), "# my keys\n[default]\naws_access_key_id = AKIDLONGTERM\naws_secret_access_key = S\n\
+            \n[dev]\naws_access_key_id = ASIA1\naws_secret_access_key = SECRET\naws_session_token = TOKEN\n\
+            stscli_expiration = 2030-01-01T00:00:00+00:00\n");
+    }
+
+    #[test]
+    fn replaces_an_existing_section_in_place() {
+        let mut file = CredentialsFile::parse("[dev]\nregion = eu-west-1\naws_access_key_id = ASIA1\n; note\n\
+            stscli_expiration = 2020-01-01T00:00:00+00:00\n\n# about prod\n[prod]\nregion = us-east-1\n");
+
+        file.set_credentials("dev", &creds("ASIA2", "2030-01-01T00:00:00Z")).unwrap();
+
+        assert_eq!(file.contents(), "[dev]\nregion = eu-west-1\naws_access_key_id = ASIA2\n; note\n\
+            stscli_expiration = 2030-01-01T00:00:00+00:00\naws_secret_access_key = SECRET\naws_session_token = TOKEN\n\
+            \n# about prod\n[prod]\nregion = us-east-1\n");
+    }
+
+    #[test]
+    fn refuses_to_overwrite_other_credentials() {
+        let original = "[default]\naws_access_key_id = AKIDLONGTERM\naws_secret_access_key = S\n";
+        let mut file = CredentialsFile::parse(original);
+
+        assert!(file.set_credentials("default", &creds("ASIA1", "2030-01-01T00:00:00Z")).is_err());
+        assert_eq!(file.contents(), original);
+    }
+
+    #[test]
+    fn keeps_windows_line_endings() {
+        let mut file = CredentialsFile::parse("[default]\r\nregion = us-east-1\r\n");
+
+        file.set_credentials("dev", &creds("ASIA1", "2030-01-01T00:00:00Z")).unwrap();
+
+        assert!(file.contents().contains("\r\n[dev]\r\naws_access_key_id = ASIA1\r\n"));
+        assert!(!file.contents().replace("\r\n", "").contains('\n'));
+    }
+
+    #[test]
+    fn prunes_only_expired_stscli_sections() {
+        let mut file = CredentialsFile::parse("[default]\naws_access_key_id = AKIDLONGTERM\n\n\
+            [old]\naws_access_key_id = ASIA1\nstscli_expiration = 2020-01-01T00:00:00+00:00\n# about new\n\
+            [new]\naws_access_key_id = ASIA2\nstscli_expiration = 2030-01-01T00:00:00+00:00\n");
+
+        let now = Utc.ymd(2025, 1, 1).and_hms(0, 0, 0);
+        assert_eq!(file.prune_expired(now), vec!["old".to_owned()]);
+        assert_eq!(file.contents(), "[default]\naws_access_key_id = AKIDLONGTERM\n\n# about new\n\
+            [new]\naws_access_key_id = ASIA2\nstscli_expiration = 2030-01-01T00:00:00+00:00\n");
+
+        assert!(file.prune_expired(now + Duration::days(3650)).contains(&"new".to_owned()));
+    }
+}
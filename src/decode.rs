@@ -0,0 +1,203 @@
+#[cfg(unix)]
+use libc;
+use regex::Regex;
+use serde_json;
+use serde_json::Value;
+use std::io;
+use std::io::Read;
+use result::*;
+
+/// Reads the message from stdin when no argument or `-` is given.
+pub fn read_message(arg: Option<&str>) -> Result<String> {
+    match arg {
+        Some(message) if message != "-" => Ok(message.to_owned()),
+        _ => {
+            let mut input = String::new();
+            try!(io::stdin().read_to_string(&mut input));
+            Ok(input)
+        }
+    }
+}
+
+/// Finds the encoded message in the input, which may be a whole pasted error, such as
+/// `An error occurred (UnauthorizedOperation) ... Encoded authorization failure message: <blob>`.
+pub fn extract_encoded_message(input: &str) -> Result<String> {
+    let after_label = Regex::new(r"(?i)encoded authorization failure message:\s*([A-Za-z0-9_+/=-]+)").unwrap();
+
+    if let Some(captures) = after_label.captures(input) {
+        return Ok(captures.at(1).unwrap().to_owned());
+    }
+
+    // otherwise take the longest word, since the blob is much longer than anything else in an error
+    let word = Regex::new(r"[A-Za-z0-9_+/=-]+").unwrap();
+
+    word.find_iter(input)
+        .map(|(start, end)| &input[start..end])
+        .max_by_key(|w| w.len())
+        .map(|w| w.to_owned())
+        .ok_or_else(|| StsCliError::Error("no encoded authorization failure message found".to_owned()))
+}
+
+/// Prints the parts of a decoded message that explain the failure, then the whole message.
+pub fn print_decoded_message(decoded: &str, highlight: bool) -> Result<()> {
+    print!("{}", try!(format_decoded_message(decoded, highlight)));
+
+    Ok(())
+}
+
+fn format_decoded_message(decoded: &str, highlight: bool) -> Result<String> {
+    let message: Value = try!(serde_json::from_str(decoded));
+    let mut out = String::new();
+
+    let bold = |text: &str| if highlight { format!("\x1b[1m{}\x1b[0m", text) } else { text.to_owned() };
+    let label = |name: &str| bold(&format!("{}:", name));
+    let field = |pointer: &str| message.pointer(pointer).map(value_text).unwrap_or("-".to_owned());
+
+    out.push_str(&format!("{} {}\n", label("Allowed"), field("/allowed")));
+    out.push_str(&format!("{} {}\n", label("Explicit deny"), field("/explicitDeny")));
+    out.push_str(&format!("{} {}\n", label("Principal"), field("/context/principal/arn")));
+    out.push_str(&format!("{} {}\n", label("Action"), field("/context/action")));
+    out.push_str(&format!("{} {}\n", label("Resource"), field("/context/resource")));
+
+    let statements = message.pointer("/matchedStatements/items").and_then(|v| v.as_array()).cloned().unwrap_or(vec![]);
+    out.push_str(&format!("{} {}\n", label("Matched statements"), statements.len()));
+
+    for statement in &statements {
+        let effect = statement.pointer("/effect").map(value_text).unwrap_or("-".to_owned());
+        let effect = match (highlight, &effect[..]) {
+            (true, "DENY") => format!("\x1b[1;31m{}\x1b[0m", effect),
+            (true, "ALLOW") => format!("\x1b[1;32m{}\x1b[0m", effect),
+            _ => effect,
+        };
+
+        out.push_str(&format!("  {} {}\n", effect, statement.pointer("/statementId").map(value_text).unwrap_or("-".to_owned())));
+        out.push_str(&format!("    actions: {}\n", item_values(statement, "/actions/items").join(", ")));
+        out.push_str(&format!("    resources: {}\n", item_values(statement, "/resources/items").join(", ")));
+
+        let principals = item_values(statement, "/principals/items");
+        if !principals.is_empty() {
+            out.push_str(&format!("    principals: {}\n", principals.join(", ")));
+        }
+
+        if let Some(conditions) = statement.pointer("/conditions/items").and_then(|v| v.as_array()) {
+            for condition in conditions {
+                out.push_str(&format!("    condition: {}\n", condition));
+            }
+        }
+    }
+
+    if let Some(conditions) = message.pointer("/context/conditions/items").and_then(|v| v.as_array()) {
+        out.push_str(&format!("{}\n", label("Context")));
+
+        for condition in conditions {
+            let key = condition.pointer("/key").map(value_text).unwrap_or("-".to_owned());
+            out.push_str(&format!("  {} = {}\n", key, item_values(condition, "/values/items").join(", ")));
+        }
+    }
+
+    out.push('\n');
+    out.push_str(&try!(serde_json::to_string_pretty(&message)));
+    out.push('\n');
+
+    Ok(out)
+}
+
+/// Only highlight when a person is likely to be reading the output.
+#[cfg(unix)]
+pub fn stdout_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+#[cfg(not(unix))]
+pub fn stdout_is_terminal() -> bool {
+    false
+}
+
+fn value_text(value: &Value) -> String {
+    match *value {
+        Value::String(ref s) => s.clone(),
+        ref other => other.to_string(),
+    }
+}
+
+/// The `value`s of the `{"items": [{"value": ...}]}` lists the decoded message uses.
+fn item_values(value: &Value, pointer: &str) -> Vec<String> {
+    value.pointer(pointer)
+        .and_then(|v| v.as_array())
+        .map(|items| items.iter().map(|item| item.pointer("/value").map(value_text).unwrap_or("-".to_owned())).collect())
+        .unwrap_or(vec![])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOB: &'static str = "Xk3-eHlR_w8Q+9n/T2sLq0VbYcZ1aPdFgUoJiM4rN7tKx6yE5uWjS=";
+
+    #[test]
+    fn extracts_the_message_after_the_label() {
+        let error = format!("An error occurred (UnauthorizedOperation) when calling the RunInstances operation: \
+            You are not authorized to perform this operation. Encoded authorization failure message: {}\n", BLOB);
+
+        assert_eq!(extract_encoded_message(&error).unwrap(), BLOB);
+        assert_eq!(extract_encoded_message(&format!("encoded AUTHORIZATION failure message:{}", BLOB)).unwrap(), BLOB);
+    }
+
+    #[test]
+    fn extracts_a_bare_message() {
+        assert_eq!(extract_encoded_message(BLOB).unwrap(), BLOB);
+        assert_eq!(extract_encoded_message(&format!("  {}\n", BLOB)).unwrap(), BLOB);
+        assert_eq!(extract_encoded_message(&format!("\"{}\" was returned", BLOB)).unwrap(), BLOB);
+    }
+
+    #[test]
+    fn needs_a_message() {
+        assert!(extract_encoded_message("").is_err());
+        assert!(extract_encoded_message(" \n\t ").is_err());
+    }
+
+    const DECODED: &'static str = r#"{
+        "allowed": false,
+        "explicitDeny": true,
+        "matchedStatements": {"items": [
+            {"statementId": "DenyBigInstances", "effect": "DENY",
+             "principals": {"items": [{"value": "AROAEXAMPLE"}]},
+             "actions": {"items": [{"value": "ec2:RunInstances"}]},
+             "resources": {"items": [{"value": "*"}]},
+             "conditions": {"items": [{"key": "ec2:InstanceType", "values": {"items": [{"value": "t3.micro"}]}}]}},
+            {"statementId": "AllowEc2", "effect": "ALLOW",
+             "actions": {"items": [{"value": "ec2:*"}]},
+             "resources": {"items": [{"value": "*"}]}}
+        ]},
+        "context": {
+            "principal": {"id": "AROAEXAMPLE:me", "arn": "arn:aws:sts::111111111111:assumed-role/dev/me"},
+            "action": "ec2:RunInstances",
+            "resource": "arn:aws:ec2:eu-west-1:111111111111:instance/*",
+            "conditions": {"items": [{"key": "ec2:InstanceType", "values": {"items": [{"value": "m5.24xlarge"}]}}]}
+        }
+    }"#;
+
+    #[test]
+    fn lists_each_matched_statement() {
+        let out = format_decoded_message(DECODED, false).unwrap();
+
+        assert!(out.contains("Matched statements: 2\n  DENY DenyBigInstances\n    actions: ec2:RunInstances\n    resources: *\n    principals: AROAEXAMPLE\n"));
+        assert!(out.contains("  ALLOW AllowEc2\n    actions: ec2:*\n    resources: *\n"));
+        assert!(out.contains("ec2:InstanceType = m5.24xlarge\n"));
+        assert!(!out.contains("\x1b["));
+    }
+
+    #[test]
+    fn highlights_effects_and_labels() {
+        let out = format_decoded_message(DECODED, true).unwrap();
+
+        assert!(out.contains("\x1b[1mMatched statements:\x1b[0m 2\n"));
+        assert!(out.contains("  \x1b[1;31mDENY\x1b[0m DenyBigInstances\n"));
+        assert!(out.contains("  \x1b[1;32mALLOW\x1b[0m AllowEc2\n"));
+    }
+
+    #[test]
+    fn rejects_invalid_json() {
+        assert!(format_decoded_message("not json", false).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use chrono::Utc;
+use rusoto_core::{AwsCredentials, DispatchSignedRequest, HttpDispatchError, HttpResponse, SignedRequest};
+use rusoto_core::param::Params;
+
+/// Adds request parameters that the generated STS client doesn't know about, such as `PolicyArns`.
+///
+/// The request has already been signed by the client, so it is copied, extended and signed again.
+pub struct ExtraParamsDispatcher<D> {
+    dispatcher: D,
+    params: Params,
+    credentials: Option<AwsCredentials>,
+}
+
+impl<D> ExtraParamsDispatcher<D> where D: DispatchSignedRequest {
+    pub fn new(dispatcher: D, params: Params, credentials: AwsCredentials) -> ExtraParamsDispatcher<D> {
+        ExtraParamsDispatcher {
+            dispatcher: dispatcher,
+            params: params,
+            credentials: Some(credentials),
+        }
+    }
+
+    /// Sends requests unsigned, for calls like AssumeRoleWithWebIdentity that are authenticated by a token instead.
+    pub fn anonymous(dispatcher: D, params: Params) -> ExtraParamsDispatcher<D> {
+        ExtraParamsDispatcher {
+            dispatcher: dispatcher,
+            params: params,
+            credentials: None,
+        }
+    }
+}
+
+impl<D> DispatchSignedRequest for ExtraParamsDispatcher<D> where D: DispatchSignedRequest {
+    fn dispatch(&self, request: &SignedRequest) -> Result<HttpResponse, HttpDispatchError> {
+        if self.params.is_empty() && self.credentials.is_some() {
+            return self.dispatcher.dispatch(request);
+        }
+
+        let mut extended = SignedRequest::new(&request.method, &request.service, &request.region, &request.path);
+        extended.headers = request.headers.clone();
+        extended.scheme = request.scheme.clone();
+        extended.set_hostname(request.hostname.clone());
+        extended.set_payload(request.payload.clone());
+
+        let mut params = request.params.clone();
+        params.extend(self.params.clone());
+        extended.set_params(params);
+
+        match self.credentials {
+            Some(ref credentials) => extended.sign(credentials),
+            None => {
+                // signing also builds the query string, so sign with placeholder keys and then drop the signature
+                extended.sign(&AwsCredentials::new("", "", None, Utc::now()));
+                extended.remove_header("authorization");
+                extended.remove_header("x-amz-security-token");
+            }
+        }
+
+        self.dispatcher.dispatch(&extended)
+    }
+}
@@ -0,0 +1,130 @@
+use result::*;
+
+const MINUTE: i64 = 60;
+const HOUR: i64 = 60 * MINUTE;
+
+/// The kinds of STS session, which AWS allows to last for different lengths of time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SessionKind {
+    AssumeRole,
+    /// A role assumed using the credentials of another role session.
+    ChainedAssumeRole,
+    GetSessionToken,
+    GetFederationToken,
+}
+
+impl SessionKind {
+    fn limits(&self) -> (i64, i64) {
+        match *self {
+            SessionKind::AssumeRole => (15 * MINUTE, 12 * HOUR),
+            SessionKind::ChainedAssumeRole => (15 * MINUTE, HOUR),
+            SessionKind::GetSessionToken => (15 * MINUTE, 36 * HOUR),
+            SessionKind::GetFederationToken => (15 * MINUTE, 36 * HOUR),
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match *self {
+            SessionKind::AssumeRole => "AssumeRole sessions",
+            SessionKind::ChainedAssumeRole => "sessions for chained roles (assumed using another role's session)",
+            SessionKind::GetSessionToken => "GetSessionToken sessions",
+            SessionKind::GetFederationToken => "GetFederationToken sessions",
+        }
+    }
+}
+
+/// Parses a duration given in seconds, or with an `s`, `m` or `h` suffix, e.g. `900`, `45m` or `12h`.
+pub fn parse_duration(s: &str) -> Result<i64> {
+    let s = s.trim();
+
+    let (number, unit) = match s.chars().last() {
+        Some('s') => (&s[..s.len() - 1], 1),
+        Some('m') => (&s[..s.len() - 1], MINUTE),
+        Some('h') => (&s[..s.len() - 1], HOUR),
+        _ => (s, 1),
+    };
+
+    number.parse::<i64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| StsCliError::Error(format!("invalid duration {}: expected seconds, or a number followed by s, m or h, e.g. 900, 45m or 12h", s)))
+}
+
+/// Formats a number of seconds the way `parse_duration` accepts them, using the largest whole unit.
+pub fn format_duration(seconds: i64) -> String {
+    if seconds != 0 && seconds % HOUR == 0 {
+        format!("{}h", seconds / HOUR)
+    } else if seconds != 0 && seconds % MINUTE == 0 {
+        format!("{}m", seconds / MINUTE)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Checks a session duration against the limits STS enforces, so we can explain which one applies.
+pub fn check_duration(seconds: i64, kind: SessionKind) -> Result<()> {
+    let (min, max) = kind.limits();
+
+    if seconds < min || seconds > max {
+        return Err(StsCliError::Error(format!("duration {} is out of range: {} must last between {} and {}",
+            format_duration(seconds), kind.description(), format_duration(min), format_duration(max))));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse_duration("900").unwrap(), 900);
+        assert_eq!(parse_duration("900s").unwrap(), 900);
+        assert_eq!(parse_duration("45m").unwrap(), 2700);
+        assert_eq!(parse_duration(" 12h ").unwrap(), 43200);
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for s in &["", "h", "1d", "1.5h", "m5", "one hour", "9223372036854775807h"] {
+            assert!(parse_duration(s).is_err(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn formats_with_the_largest_unit() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(90), "90s");
+        assert_eq!(format_duration(900), "15m");
+        assert_eq!(format_duration(5400), "90m");
+        assert_eq!(format_duration(43200), "12h");
+
+        for &seconds in &[1, 59, 60, 3599, 3600, 129600] {
+            assert_eq!(parse_duration(&format_duration(seconds)).unwrap(), seconds);
+        }
+    }
+
+    #[test]
+    fn checks_the_limits_for_each_kind() {
+        assert!(check_duration(899, SessionKind::AssumeRole).is_err());
+        assert!(check_duration(900, SessionKind::AssumeRole).is_ok());
+        assert!(check_duration(43200, SessionKind::AssumeRole).is_ok());
+        assert!(check_duration(43201, SessionKind::AssumeRole).is_err());
+
+        assert!(check_duration(3600, SessionKind::ChainedAssumeRole).is_ok());
+        assert!(check_duration(3601, SessionKind::ChainedAssumeRole).is_err());
+
+        assert!(check_duration(129600, SessionKind::GetSessionToken).is_ok());
+        assert!(check_duration(129601, SessionKind::GetFederationToken).is_err());
+    }
+
+    #[test]
+    fn explains_which_limit_applies() {
+        let err = check_duration(7200, SessionKind::ChainedAssumeRole).unwrap_err().to_string();
+
+        assert!(err.contains("2h"), "{}", err);
+        assert!(err.contains("chained roles"), "{}", err);
+        assert!(err.contains("between 15m and 1h"), "{}", err);
+    }
+}
@@ -0,0 +1,141 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use result::*;
+
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+
+/// Creates a directory and its parents, readable only by the current user.
+pub fn create_private_dir_all(path: &Path) -> Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    builder.mode(0o700);
+
+    try!(builder.create(path));
+
+    Ok(())
+}
+
+/// Creates a new directory readable only by the current user, failing if it already exists.
+pub fn create_private_dir(path: &Path) -> Result<()> {
+    let mut builder = fs::DirBuilder::new();
+
+    #[cfg(unix)]
+    builder.mode(0o700);
+
+    try!(builder.create(path));
+
+    Ok(())
+}
+
+/// Replaces the contents of a file atomically, creating it with 0600 permissions.
+///
+/// The data is written to a temporary file in the same directory, which is then
+/// renamed over the destination so readers never see a partially written file.
+pub fn write_private_file_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = try!(temp_path_for(path));
+
+    let result = write_private_file(&tmp_path, contents)
+        .and_then(|_| fs::rename(&tmp_path, path).map_err(StsCliError::from));
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    result
+}
+
+fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = try!(options.open(path));
+    try!(file.write_all(contents));
+    try!(file.sync_all());
+
+    Ok(())
+}
+
+fn temp_path_for(path: &Path) -> Result<PathBuf> {
+    let file_name = try!(path.file_name()
+        .ok_or_else(|| StsCliError::Error(format!("not a file path: {}", path.display()))));
+
+    let mut tmp_name = ".".to_owned();
+    tmp_name.push_str(&file_name.to_string_lossy());
+    tmp_name.push_str(&format!(".{}.tmp", process::id()));
+
+    Ok(path.with_file_name(tmp_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::process;
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("stscli-files-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn contents(path: &Path) -> String {
+        let mut contents = String::new();
+        fs::File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn replaces_files_leaving_no_temporary_file() {
+        let dir = temp_dir("replace");
+        create_private_dir_all(&dir.join("a/b")).unwrap();
+        let path = dir.join("a/b/credentials");
+
+        write_private_file_atomic(&path, b"first").unwrap();
+        write_private_file_atomic(&path, b"second").unwrap();
+
+        assert_eq!(contents(&path), "second");
+        assert_eq!(fs::read_dir(dir.join("a/b")).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn refuses_a_directory_that_already_exists() {
+        let dir = temp_dir("exists");
+        fs::create_dir(&dir).unwrap();
+
+        assert!(create_private_dir(&dir).is_err());
+        assert!(create_private_dir_all(&dir).is_ok());
+    }
+
+    #[test]
+    fn fails_without_a_file_name_or_directory() {
+        let dir = temp_dir("fail");
+
+        assert!(write_private_file_atomic(&dir.join("missing/credentials"), b"x").is_err());
+        assert!(write_private_file_atomic(Path::new("/"), b"x").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_the_user_can_read_them() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("permissions");
+        create_private_dir_all(&dir).unwrap();
+        write_private_file_atomic(&dir.join("credentials"), b"secret").unwrap();
+
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777, 0o700);
+        assert_eq!(fs::metadata(dir.join("credentials")).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,287 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use result::*;
+
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_LENGTH: u64 = 64 * 1024;
+
+/// A minimal HTTP/1.1 server for answering credential requests from local processes.
+///
+/// Each connection is handled on its own thread and closed after one response.
+/// Request bodies are read and discarded.
+pub fn serve<H>(listener: TcpListener, handler: H) -> thread::JoinHandle<()> where H: Fn(&Request) -> Response + Send + Sync + 'static {
+    let handler = Arc::new(handler);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let handler = handler.clone();
+
+                    thread::spawn(move || {
+                        if let Err(err) = handle_connection(stream, &*handler) {
+                            debug!("http connection failed: {}", err);
+                        }
+                    });
+                },
+                Err(err) => warn!("http accept failed: {}", err),
+            }
+        }
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// The path without the query string.
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Gets the first header with the given name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|header| header.0.to_lowercase() == name.to_lowercase())
+            .map(|header| header.1.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: String) -> Response {
+        Response {
+            status: status,
+            headers: vec![("Content-Type".to_owned(), content_type.to_owned())],
+            body: body,
+        }
+    }
+
+    pub fn json(status: u16, body: String) -> Response {
+        Response::new(status, "application/json", body)
+    }
+
+    pub fn text(status: u16, body: &str) -> Response {
+        Response::new(status, "text/plain", body.to_owned())
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+fn handle_connection<H>(stream: TcpStream, handler: &H) -> Result<()> where H: Fn(&Request) -> Response {
+    try!(stream.set_read_timeout(Some(Duration::from_secs(10))));
+    try!(stream.set_write_timeout(Some(Duration::from_secs(10))));
+
+    let mut reader = BufReader::new(try!(stream.try_clone()));
+
+    let response = match read_request(&mut reader) {
+        Ok(request) => {
+            debug!("http {} {}", request.method, request.path);
+            handler(&request)
+        },
+        Err(err) => {
+            debug!("bad http request: {}", err);
+            Response::text(400, "bad request")
+        },
+    };
+
+    write_response(stream, &response)
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Request> {
+    let mut line = String::new();
+    try!(reader.read_line(&mut line));
+
+    let (method, path) = {
+        let mut parts = line.split_whitespace();
+
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") => (method.to_owned(), path.to_owned()),
+            _ => return Err(StsCliError::Error(format!("invalid request line {:?}", line))),
+        }
+    };
+
+    let mut headers = vec![];
+
+    loop {
+        let mut line = String::new();
+        if try!(reader.read_line(&mut line)) == 0 {
+            return Err(StsCliError::Error("connection closed while reading headers".to_owned()));
+        }
+
+        let line = line.trim();
+        if line.is_empty() {
+            break;
+        }
+
+        if headers.len() == MAX_HEADERS {
+            return Err(StsCliError::Error("too many headers".to_owned()));
+        }
+
+        match line.find(':') {
+            Some(i) => headers.push((line[..i].trim().to_owned(), line[i + 1..].trim().to_owned())),
+            None => return Err(StsCliError::Error(format!("invalid header {:?}", line))),
+        }
+    }
+
+    let (path, query) = match path.find('?') {
+        Some(i) => (path[..i].to_owned(), Some(path[i + 1..].to_owned())),
+        None => (path, None),
+    };
+
+    let request = Request {
+        method: method,
+        path: path,
+        query: query,
+        headers: headers,
+    };
+
+    let content_length = match request.header("Content-Length") {
+        Some(length) => try!(length.parse::<u64>().map_err(|_| StsCliError::Error(format!("invalid Content-Length {}", length)))),
+        None => 0,
+    };
+
+    if content_length > MAX_BODY_LENGTH {
+        return Err(StsCliError::Error("request body too large".to_owned()));
+    }
+
+    try!(reader.by_ref().take(content_length).read_to_end(&mut vec![]));
+
+    Ok(request)
+}
+
+fn write_response<W: Write>(mut stream: W, response: &Response) -> Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason_phrase(response.status));
+
+    for header in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", header.0, header.1));
+    }
+
+    head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", response.body.len()));
+
+    try!(stream.write_all(head.as_bytes()));
+    try!(stream.write_all(response.body.as_bytes()));
+    try!(stream.flush());
+
+    Ok(())
+}
+
+/// Sends a raw request to a server and returns the whole response.
+#[cfg(test)]
+pub fn send(address: &str, request: &[u8]) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(request).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+    use std::net::TcpListener;
+    use super::*;
+
+    fn parse(request: &str) -> Result<Request> {
+        read_request(&mut Cursor::new(request.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn parses_the_request_line_and_headers() {
+        let request = parse("PUT /latest/api/token HTTP/1.1\r\nHost: localhost\r\nX-Ttl:  21600 \r\n\r\n").unwrap();
+
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/latest/api/token");
+        assert_eq!(request.header("x-ttl"), Some("21600"));
+        assert_eq!(request.header("HOST"), Some("localhost"));
+        assert_eq!(request.header("Authorization"), None);
+        assert_eq!(request.query, None);
+    }
+
+    #[test]
+    fn splits_off_the_query_string() {
+        let request = parse("GET /latest/meta-data/iam/security-credentials/?x=1&y HTTP/1.1\r\n\r\n").unwrap();
+
+        assert_eq!(request.path, "/latest/meta-data/iam/security-credentials/");
+        assert_eq!(request.query, Some("x=1&y".to_owned()));
+        assert_eq!(parse("GET /? HTTP/1.1\r\n\r\n").unwrap().query, Some("".to_owned()));
+    }
+
+    #[test]
+    fn accepts_bare_line_feeds() {
+        assert_eq!(parse("GET / HTTP/1.0\nHost: localhost\n\n").unwrap().path, "/");
+    }
+
+    #[test]
+    fn reads_and_discards_the_body() {
+        let mut reader = Cursor::new(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET".to_vec());
+
+        read_request(&mut reader).unwrap();
+
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "GET");
+    }
+
+    #[test]
+    fn rejects_bad_requests() {
+        assert!(parse("").is_err());
+        assert!(parse("GET /\r\n\r\n").is_err());
+        assert!(parse("GET / SPDY/3\r\n\r\n").is_err());
+        assert!(parse("GET / HTTP/1.1\r\nno colon\r\n\r\n").is_err());
+        assert!(parse("GET / HTTP/1.1\r\nHost: localhost\r\n").is_err());
+        assert!(parse("POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n").is_err());
+        assert!(parse("POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n").is_err());
+        assert!(parse(&format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_LENGTH + 1)).is_err());
+
+        let many_headers: String = (0..MAX_HEADERS + 1).map(|i| format!("X-{}: {}\r\n", i, i)).collect();
+        assert!(parse(&format!("GET / HTTP/1.1\r\n{}\r\n", many_headers)).is_err());
+    }
+
+    #[test]
+    fn writes_content_length_and_closes() {
+        let mut out = vec![];
+
+        write_response(&mut out, &Response::text(404, "not found").with_header("X-Test", "1")).unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nX-Test: 1\r\nContent-Length: 9\r\nConnection: close\r\n\r\nnot found");
+    }
+
+    #[test]
+    fn answers_bad_input_with_400() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        serve(listener, |request| Response::text(200, &request.path));
+
+        assert!(send(&address, b"GET /ok HTTP/1.1\r\n\r\n").ends_with("\r\n\r\n/ok"));
+        assert!(send(&address, b"\x00\xff garbage\r\n\r\n").starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(send(&address, b"GET / HTTP/1.1\r\nContent-Length: x\r\n\r\n").starts_with("HTTP/1.1 400 "));
+    }
+}
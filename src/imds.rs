@@ -0,0 +1,296 @@
+use chrono::{DateTime, Duration, Utc};
+use rusoto_core::AwsCredentials;
+use serde_json;
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::sync::Mutex;
+use std::thread;
+use http;
+use serve::{random_string, RefreshingCredentials};
+use result::*;
+
+const TOKEN_PATH: &'static str = "/latest/api/token";
+const CREDENTIALS_PATH: &'static str = "/latest/meta-data/iam/security-credentials/";
+const TOKEN_TTL_HEADER: &'static str = "X-aws-ec2-metadata-token-ttl-seconds";
+const TOKEN_HEADER: &'static str = "X-aws-ec2-metadata-token";
+const MAX_TOKEN_TTL_SECONDS: i64 = 21600;
+
+/// The JSON EC2 returns for an instance profile's credentials.
+#[derive(Debug, Serialize)]
+struct InstanceCredentials<'a> {
+    #[serde(rename = "Code")]
+    code: &'a str,
+    #[serde(rename = "LastUpdated")]
+    last_updated: String,
+    #[serde(rename = "Type")]
+    credentials_type: &'a str,
+    #[serde(rename = "AccessKeyId")]
+    access_key_id: &'a str,
+    #[serde(rename = "SecretAccessKey")]
+    secret_access_key: &'a str,
+    #[serde(rename = "Token", skip_serializing_if = "Option::is_none")]
+    token: Option<&'a str>,
+    #[serde(rename = "Expiration")]
+    expiration: String,
+}
+
+/// Session tokens for IMDSv2, and when they expire.
+struct Tokens {
+    tokens: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl Tokens {
+    fn issue(&self, ttl_seconds: i64) -> Result<String> {
+        let token = try!(random_string());
+        let now = Utc::now();
+
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, expires_at| *expires_at > now);
+        tokens.insert(token.clone(), now + Duration::seconds(ttl_seconds));
+
+        Ok(token)
+    }
+
+    fn is_valid(&self, token: &str) -> bool {
+        self.tokens.lock().unwrap()
+            .get(token)
+            .map(|expires_at| *expires_at > Utc::now())
+            .unwrap_or(false)
+    }
+}
+
+/// Emulates the parts of the EC2 instance metadata service that hand out instance profile credentials.
+///
+/// Like IMDSv2, a session token from `PUT /latest/api/token` is needed to read credentials.
+pub struct ImdsServer {
+    pub url: String,
+    thread: thread::JoinHandle<()>,
+}
+
+impl ImdsServer {
+    /// Only loopback addresses are allowed unless `allow_remote` is set, as anyone who can reach the
+    /// server can get a token.
+    pub fn start<A: ToSocketAddrs>(address: A, allow_remote: bool, role_name: String, credentials: RefreshingCredentials) -> Result<ImdsServer> {
+        let addresses: Vec<SocketAddr> = try!(address.to_socket_addrs()).collect();
+
+        if let Some(address) = addresses.iter().find(|a| !a.ip().is_loopback()) {
+            if !allow_remote {
+                return Err(StsCliError::Error(format!(
+                    "{} isn't a loopback address, so other hosts could read the credentials. Use --allow-remote if that is intended", address)));
+            }
+        }
+
+        let listener = try!(TcpListener::bind(&addresses[..]));
+        let url = format!("http://{}/", try!(listener.local_addr()));
+
+        let tokens = Tokens { tokens: Mutex::new(HashMap::new()) };
+
+        let thread = http::serve(listener, move |request| {
+            if request.path == TOKEN_PATH {
+                return issue_token(&tokens, request);
+            }
+
+            if !request.path.starts_with(CREDENTIALS_PATH) {
+                return http::Response::text(404, "not found");
+            }
+
+            if request.method != "GET" {
+                return http::Response::text(405, "method not allowed");
+            }
+
+            if !request.header(TOKEN_HEADER).map(|token| tokens.is_valid(token)).unwrap_or(false) {
+                return http::Response::text(401, "unauthorized");
+            }
+
+            let name = &request.path[CREDENTIALS_PATH.len()..];
+
+            if name.is_empty() {
+                http::Response::text(200, &role_name)
+            } else if name == role_name {
+                match credentials.credentials().and_then(|creds| instance_credentials_json(&creds)) {
+                    Ok(body) => http::Response::json(200, body),
+                    Err(err) => {
+                        warn!("failed to get credentials to serve: {}", err);
+                        http::Response::text(500, "failed to get credentials")
+                    },
+                }
+            } else {
+                http::Response::text(404, "not found")
+            }
+        });
+
+        Ok(ImdsServer {
+            url: url,
+            thread: thread,
+        })
+    }
+
+    /// The variables that point the AWS SDKs that support it at this server.
+    pub fn vars(&self) -> HashMap<String, String> {
+        let mut vars = HashMap::new();
+
+        vars.insert("AWS_EC2_METADATA_SERVICE_ENDPOINT".to_owned(), self.url.clone());
+
+        vars
+    }
+
+    /// Serves requests until the process is killed.
+    pub fn wait(self) -> Result<()> {
+        self.thread.join().map_err(|_| StsCliError::Error("the metadata server stopped".to_owned()))
+    }
+}
+
+#[allow(clippy::manual_range_contains)] // RangeInclusive::contains is newer than the Rust this crate targets
+fn issue_token(tokens: &Tokens, request: &http::Request) -> http::Response {
+    if request.method != "PUT" {
+        return http::Response::text(405, "method not allowed");
+    }
+
+    // EC2 refuses token requests that went through a proxy
+    if request.header("X-Forwarded-For").is_some() {
+        return http::Response::text(403, "forbidden");
+    }
+
+    let ttl_seconds = match request.header(TOKEN_TTL_HEADER).and_then(|ttl| ttl.parse::<i64>().ok()) {
+        Some(ttl) if ttl >= 1 && ttl <= MAX_TOKEN_TTL_SECONDS => ttl,
+        _ => return http::Response::text(400, "bad request"),
+    };
+
+    match tokens.issue(ttl_seconds) {
+        Ok(token) => http::Response::text(200, &token).with_header(TOKEN_TTL_HEADER, &ttl_seconds.to_string()),
+        Err(err) => {
+            warn!("failed to issue metadata token: {}", err);
+            http::Response::text(500, "failed to issue token")
+        },
+    }
+}
+
+fn instance_credentials_json(creds: &AwsCredentials) -> Result<String> {
+    Ok(try!(serde_json::to_string(&InstanceCredentials {
+        code: "Success",
+        last_updated: Utc::now().to_rfc3339(),
+        credentials_type: "AWS-HMAC",
+        access_key_id: creds.aws_access_key_id(),
+        secret_access_key: creds.aws_secret_access_key(),
+        token: creds.token().as_ref().map(|s| s.as_str()),
+        expiration: creds.expires_at().to_rfc3339(),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use rusoto_core::AwsCredentials;
+    use serde_json;
+    use serde_json::Value;
+    use config::Config;
+    use http;
+    use serve::RefreshingCredentials;
+    use result::*;
+    use super::*;
+
+    fn fetch(_config: &Config) -> Result<AwsCredentials> {
+        Ok(AwsCredentials::new("KEY", "SECRET", Some("TOKEN".to_owned()), Utc::now() + Duration::hours(1)))
+    }
+
+    fn start() -> String {
+        let config = Config { cache_margin: 300, ..Default::default() };
+        let server = ImdsServer::start("127.0.0.1:0", false, "myrole".to_owned(), RefreshingCredentials::new(&config, fetch, None)).unwrap();
+
+        // the url is http://address/
+        server.url["http://".len()..server.url.len() - 1].to_owned()
+    }
+
+    /// Returns the status code and body.
+    fn request(address: &str, method: &str, path: &str, headers: &[(&str, &str)]) -> (u16, String) {
+        let headers: String = headers.iter().map(|&(k, v)| format!("{}: {}\r\n", k, v)).collect();
+        let response = http::send(address, format!("{} {} HTTP/1.1\r\n{}\r\n", method, path, headers).as_bytes());
+
+        let status = response.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let body_start = response.find("\r\n\r\n").unwrap() + 4;
+        (status, response[body_start..].to_owned())
+    }
+
+    fn token(address: &str) -> String {
+        let (status, token) = request(address, "PUT", TOKEN_PATH, &[(TOKEN_TTL_HEADER, "60")]);
+        assert_eq!(status, 200);
+        token
+    }
+
+    #[test]
+    fn serves_credentials_with_a_token() {
+        let address = start();
+        let token = token(&address);
+
+        assert_eq!(request(&address, "GET", CREDENTIALS_PATH, &[(TOKEN_HEADER, &token)]), (200, "myrole".to_owned()));
+
+        let (status, body) = request(&address, "GET", &format!("{}myrole", CREDENTIALS_PATH), &[(TOKEN_HEADER, &token)]);
+        assert_eq!(status, 200);
+        let json: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json.pointer("/Code").and_then(|v| v.as_str()), Some("Success"));
+        assert_eq!(json.pointer("/AccessKeyId").and_then(|v| v.as_str()), Some("KEY"));
+        assert_eq!(json.pointer("/Token").and_then(|v| v.as_str()), Some("TOKEN"));
+
+        assert_eq!(request(&address, "GET", &format!("{}other", CREDENTIALS_PATH), &[(TOKEN_HEADER, &token)]).0, 404);
+        assert_eq!(request(&address, "POST", CREDENTIALS_PATH, &[(TOKEN_HEADER, &token)]).0, 405);
+    }
+
+    #[test]
+    fn ignores_query_strings() {
+        let address = start();
+        let token = token(&address);
+
+        assert_eq!(request(&address, "GET", &format!("{}?x=1", CREDENTIALS_PATH), &[(TOKEN_HEADER, &token)]), (200, "myrole".to_owned()));
+        assert_eq!(request(&address, "GET", &format!("{}myrole?x=1", CREDENTIALS_PATH), &[(TOKEN_HEADER, &token)]).0, 200);
+    }
+
+    #[test]
+    fn needs_a_valid_token() {
+        let address = start();
+
+        assert_eq!(request(&address, "GET", CREDENTIALS_PATH, &[]).0, 401);
+        assert_eq!(request(&address, "GET", CREDENTIALS_PATH, &[(TOKEN_HEADER, "made-up")]).0, 401);
+    }
+
+    #[test]
+    fn checks_the_token_ttl() {
+        let address = start();
+
+        for ttl in &["1", "21600"] {
+            let (status, token) = request(&address, "PUT", TOKEN_PATH, &[(TOKEN_TTL_HEADER, ttl)]);
+            assert_eq!(status, 200);
+            assert!(!token.is_empty());
+        }
+
+        assert_eq!(request(&address, "PUT", TOKEN_PATH, &[]).0, 400);
+        for ttl in &["0", "-1", "21601", "an hour"] {
+            assert_eq!(request(&address, "PUT", TOKEN_PATH, &[(TOKEN_TTL_HEADER, ttl)]).0, 400);
+        }
+
+        assert_eq!(request(&address, "GET", TOKEN_PATH, &[(TOKEN_TTL_HEADER, "60")]).0, 405);
+        assert_eq!(request(&address, "PUT", TOKEN_PATH, &[(TOKEN_TTL_HEADER, "60"), ("X-Forwarded-For", "10.0.0.1")]).0, 403);
+    }
+
+    #[test]
+    fn tokens_expire() {
+        let tokens = Tokens { tokens: Mutex::new(HashMap::new()) };
+        let token = tokens.issue(60).unwrap();
+        assert!(tokens.is_valid(&token));
+
+        tokens.tokens.lock().unwrap().insert(token.clone(), Utc::now() - Duration::seconds(1));
+        assert!(!tokens.is_valid(&token));
+        assert!(!tokens.is_valid(""));
+    }
+
+    #[test]
+    fn refuses_remote_addresses_unless_allowed() {
+        let config = Config { cache_margin: 300, ..Default::default() };
+
+        let err = ImdsServer::start("0.0.0.0:0", false, "myrole".to_owned(), RefreshingCredentials::new(&config, fetch, None)).err().unwrap();
+        assert!(err.to_string().contains("--allow-remote"));
+
+        assert!(ImdsServer::start("0.0.0.0:0", true, "myrole".to_owned(), RefreshingCredentials::new(&config, fetch, None)).is_ok());
+        assert!(ImdsServer::start("[::1]:0", false, "myrole".to_owned(), RefreshingCredentials::new(&config, fetch, None)).is_ok()
+            || TcpListener::bind("[::1]:0").is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use serde_json;
+use std::collections::{BTreeMap, HashMap};
+use result::*;
+
+pub const DEFAULT_SECRET_NAME: &'static str = "aws-credentials";
+
+/// The annotation on the Secret saying when the credentials in it expire.
+const EXPIRATION_ANNOTATION: &'static str = "stscli/expiration";
+
+/// A `v1` Secret manifest holding the variables, for `kubectl apply -f -`.
+///
+/// Pods can load it with `envFrom`, and running stscli again replaces the credentials in it.
+pub fn secret_manifest(vars: &HashMap<String, String>, name: &str, namespace: Option<&str>) -> Result<String> {
+    try!(check_name("secret name", name, 253, true));
+    if let Some(namespace) = namespace {
+        try!(check_name("namespace", namespace, 63, false));
+    }
+
+    let mut manifest = String::new();
+
+    manifest.push_str("apiVersion: v1\nkind: Secret\nmetadata:\n");
+    manifest.push_str(&format!("  name: {}\n", try!(yaml_string(name))));
+    if let Some(namespace) = namespace {
+        manifest.push_str(&format!("  namespace: {}\n", try!(yaml_string(namespace))));
+    }
+    manifest.push_str("  labels:\n    app.kubernetes.io/managed-by: stscli\n");
+    if let Some(expiration) = vars.get("AWS_CREDENTIAL_EXPIRATION") {
+        manifest.push_str(&format!("  annotations:\n    {}: {}\n", EXPIRATION_ANNOTATION, try!(yaml_string(expiration))));
+    }
+    manifest.push_str("type: Opaque\ndata:\n");
+
+    let vars: BTreeMap<&String, &String> = vars.iter().collect();
+    for (k, v) in vars {
+        manifest.push_str(&format!("  {}: {}\n", k, v.as_bytes().to_base64(STANDARD)));
+    }
+
+    Ok(manifest)
+}
+
+/// Checks a name is a DNS subdomain, or with `dots` false a DNS label, as Kubernetes requires.
+fn check_name(what: &str, name: &str, max_len: usize, dots: bool) -> Result<()> {
+    let valid_chars = name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || (dots && c == '.'));
+    let valid_ends = name.chars().next().map(|c| c.is_ascii_alphanumeric()).unwrap_or(false)
+        && name.chars().last().map(|c| c.is_ascii_alphanumeric()).unwrap_or(false);
+
+    if valid_chars && valid_ends && name.len() <= max_len {
+        Ok(())
+    } else {
+        Err(StsCliError::Error(format!("invalid {} {}: use at most {} lowercase letters, digits{}, starting and ending with a letter or digit",
+            what, name, max_len, if dots { ", '-' and '.'" } else { " and '-'" })))
+    }
+}
+
+/// A JSON string is also a valid YAML double quoted string.
+fn yaml_string(s: &str) -> Result<String> {
+    Ok(try!(serde_json::to_string(s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::base64::FromBase64;
+    use std::collections::HashMap;
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect()
+    }
+
+    /// The decoded values under `data:`, in order.
+    fn data(manifest: &str) -> Vec<(String, String)> {
+        manifest.split("\ndata:\n").nth(1).unwrap()
+            .lines()
+            .map(|line| {
+                let (k, v) = line.trim().split_at(line.trim().find(": ").unwrap());
+                (k.to_owned(), String::from_utf8(v[2..].from_base64().unwrap()).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encodes_the_values() {
+        let manifest = secret_manifest(&vars(&[
+            ("AWS_SESSION_TOKEN", "token/with+symbols=\n"),
+            ("AWS_ACCESS_KEY_ID", "ASIAEXAMPLE"),
+        ]), DEFAULT_SECRET_NAME, None).unwrap();
+
+        assert!(manifest.starts_with("apiVersion: v1\nkind: Secret\nmetadata:\n  name: \"aws-credentials\"\n"));
+        assert!(manifest.contains("\ntype: Opaque\n"));
+        assert_eq!(data(&manifest), vec![
+            ("AWS_ACCESS_KEY_ID".to_owned(), "ASIAEXAMPLE".to_owned()),
+            ("AWS_SESSION_TOKEN".to_owned(), "token/with+symbols=\n".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn namespace_and_expiration_are_optional() {
+        let without = secret_manifest(&vars(&[("AWS_ACCESS_KEY_ID", "A")]), "creds", None).unwrap();
+        assert!(!without.contains("namespace:"));
+        assert!(!without.contains("annotations:"));
+
+        let with = secret_manifest(&vars(&[("AWS_ACCESS_KEY_ID", "A"), ("AWS_CREDENTIAL_EXPIRATION", "2026-10-17T12:00:00Z")]),
+            "creds.v2", Some("batch")).unwrap();
+        assert!(with.contains("  name: \"creds.v2\"\n  namespace: \"batch\"\n"));
+        assert!(with.contains("  annotations:\n    stscli/expiration: \"2026-10-17T12:00:00Z\"\n"));
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        let vars = vars(&[("AWS_ACCESS_KEY_ID", "A")]);
+
+        for name in &["", "Creds", "-creds", "creds-", "creds_1", "creds: x", &"a".repeat(254)] {
+            assert!(secret_manifest(&vars, name, None).is_err(), "{:?}", name);
+        }
+        assert!(secret_manifest(&vars, &"a".repeat(253), None).is_ok());
+
+        for namespace in &["", "my.ns", "NS", "ns-", &"a".repeat(64)] {
+            assert!(secret_manifest(&vars, "creds", Some(namespace)).is_err(), "{:?}", namespace);
+        }
+        assert!(secret_manifest(&vars, "creds", Some(&"a".repeat(63))).is_ok());
+    }
+}
@@ -0,0 +1,74 @@
+use env_logger;
+use log::{self, Log, LogLevel, LogMetadata, LogRecord};
+use std::env;
+
+/// Modules that log secrets at debug level. rusoto logs each request's payload and headers, with external ids,
+/// MFA codes and session tokens in them, and ini every value in the config file.
+const SECRET_LOGGING_MODULES: [&'static str; 2] = ["rusoto_core::request", "ini"];
+
+/// Logs as `env_logger` does, but leaves out the debug output of modules that would log secrets.
+struct Logger {
+    inner: env_logger::Logger,
+    hidden: Vec<&'static str>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        Log::enabled(&self.inner, metadata) && !hides(&self.hidden, metadata.level(), metadata.target())
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) {
+            Log::log(&self.inner, record);
+        }
+    }
+}
+
+/// Sets up logging from RUST_LOG, as `env_logger::init` does. The modules that log secrets are only
+/// logged at debug level when RUST_LOG names them.
+pub fn init() {
+    let hidden = hidden_modules(&env::var("RUST_LOG").unwrap_or_default());
+
+    log::set_logger(|max_level| {
+        let inner = env_logger::Logger::new();
+        max_level.set(inner.filter());
+
+        Box::new(Logger {
+            inner: inner,
+            hidden: hidden,
+        })
+    }).unwrap();
+}
+
+fn hidden_modules(spec: &str) -> Vec<&'static str> {
+    SECRET_LOGGING_MODULES.iter().cloned().filter(|module| !spec.contains(module)).collect()
+}
+
+fn hides(hidden: &[&str], level: LogLevel, target: &str) -> bool {
+    level >= LogLevel::Debug && hidden.iter().any(|module| target.starts_with(module))
+}
+
+#[cfg(test)]
+mod tests {
+    use log::LogLevel;
+    use super::*;
+
+    #[test]
+    fn hides_debug_output_of_modules_that_log_secrets() {
+        let hidden = hidden_modules("debug");
+
+        assert!(hides(&hidden, LogLevel::Debug, "rusoto_core::request"));
+        assert!(hides(&hidden, LogLevel::Trace, "ini::ini"));
+        assert!(!hides(&hidden, LogLevel::Info, "rusoto_core::request"));
+        assert!(!hides(&hidden, LogLevel::Debug, "rusoto_core::signature"));
+        assert!(!hides(&hidden, LogLevel::Debug, "stscli"));
+    }
+
+    #[test]
+    fn shows_modules_that_are_asked_for() {
+        let hidden = hidden_modules("debug,rusoto_core::request=debug");
+
+        assert!(!hides(&hidden, LogLevel::Debug, "rusoto_core::request"));
+        assert!(hides(&hidden, LogLevel::Debug, "ini::ini"));
+    }
+}
@@ -9,16 +9,44 @@ extern crate env_logger;
 extern crate regex;
 extern crate ini;
 extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate serde_json;
+extern crate chrono;
+extern crate libc;
+extern crate rand;
+extern crate rustc_serialize;
+extern crate xml;
 
 pub mod print;
 pub mod result;
 pub mod config;
 pub mod aws_config;
+pub mod cache;
+pub mod files;
+pub mod duration;
+pub mod mfa;
+pub mod provider;
+pub mod dispatch;
+pub mod policy;
+pub mod tags;
+pub mod web_identity;
+pub mod saml;
+pub mod credentials_file;
+pub mod http;
+pub mod serve;
+pub mod imds;
+pub mod decode;
+pub mod session;
+pub mod logging;
+pub mod signals;
+pub mod shell;
+pub mod output_file;
+pub mod k8s;
 
-use clap::{Arg, ArgMatches, App, SubCommand};
+use clap::{Arg, ArgMatches, App, AppSettings, SubCommand};
 use std::io::Write;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsString;
 use std::process;
 use std::path;
@@ -28,9 +56,13 @@ use rusoto_sts::*;
 use print::*;
 use result::*;
 use config::*;
+use provider::*;
+use dispatch::*;
+use session::Session;
+use rusoto_core::param::Params;
 
 pub fn main() {
-    env_logger::init().unwrap();
+    logging::init();
     
     let mut stderr = std::io::stderr();
 
@@ -88,36 +120,240 @@ pub fn main() {
             .help("The code from the MFA device.")
             .takes_value(true)
             )
+        .arg(Arg::with_name("external_id")
+            .long("external-id")
+            .value_name("ID")
+            .help("The external id required by the trust policy of the role to assume.")
+            .takes_value(true)
+            )
+        .arg(Arg::with_name("token_code_command")
+            .long("token-code-command")
+            .value_name("COMMAND")
+            .help("A shell command that prints the code from the MFA device, e.g. an OATH tool. Otherwise you will be asked for the code on the terminal.")
+            .takes_value(true)
+            )
+        .arg(Arg::with_name("duration")
+            .long("duration")
+            .value_name("DURATION")
+            .help("How long the session tokens should last for, in seconds or with a unit, e.g. 900, 45m or 12h")
+            .takes_value(true)
+            )
+        .arg(Arg::with_name("policy_file")
+            .long("policy-file")
+            .value_name("FILE")
+            .help("A JSON IAM policy that further restricts the permissions of the session. Use - to read it from stdin.")
+            .takes_value(true)
+            )
+        .arg(Arg::with_name("policy_arn")
+            .long("policy-arn")
+            .value_name("ARN")
+            .help("The ARN of a managed policy that further restricts the permissions of the session. May be repeated.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            )
+        .arg(Arg::with_name("tag")
+            .long("tag")
+            .value_name("KEY=VALUE")
+            .help("A session tag to attach to the role session. May be repeated.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            )
+        .arg(Arg::with_name("transitive_tag")
+            .long("transitive-tag")
+            .value_name("KEY")
+            .help("The key of a session tag that should pass on to roles assumed using the session. May be repeated.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            )
+        .arg(Arg::with_name("source_identity")
+            .long("source-identity")
+            .value_name("IDENTITY")
+            .help("The source identity to record for the role session, e.g. your user name")
+            .takes_value(true)
+            )
+        .arg(Arg::with_name("web_identity_token_file")
+            .long("web-identity-token-file")
+            .value_name("FILE")
+            .help("A file containing an OpenID Connect token to exchange for credentials with AssumeRoleWithWebIdentity")
+            .takes_value(true)
+            )
+        .arg(Arg::with_name("no_cache")
+            .long("no-cache")
+            .help("Don't read or write the credentials cache in ~/.aws/stscli/cache")
+            .takes_value(false)
+            )
+        .arg(Arg::with_name("refresh")
+            .long("refresh")
+            .help("Ignore any cached credentials and fetch new ones, updating the cache")
+            .takes_value(false)
+            )
+        .arg(Arg::with_name("cache_margin")
+            .long("cache-margin")
+            .value_name("SECONDS")
+            .help("Stop using cached credentials this many seconds before they expire. default 300")
+            .takes_value(true)
+            )
         .subcommand(SubCommand::with_name("get")
             .about("get some fresh session tokens and display them")
             .version("1.0")
             .author("various")
-            .arg(Arg::with_name("export")
-                .long("export")
-                .short("e")
+            .args(&output_args())
+            )
+        .subcommand(SubCommand::with_name("exec")
+            .about("runs a command with session tokens injected into the environment")
+            .version("1.0")
+            .author("various")
+            .arg(Arg::with_name("command")
+                .long("command")
+                .required(true)
+                .takes_value(true)
+                .index(1)
+                .multiple(true)
+                .help("shell command to run")
+                )
+            .arg(Arg::with_name("serve")
+                .long("serve")
                 .required(false)
                 .takes_value(false)
-                .help("print the variables for exporting to a shell")
+                .help("give the command a local credentials endpoint that refreshes the session, instead of the keys. implies --fork")
                 )
-            .arg(Arg::with_name("format")
-                .long("format")
-                .short("f")
+            .arg(Arg::with_name("isolate")
+                .long("isolate")
+                .required(false)
+                .takes_value(false)
+                .help("run the command with only the session's variables and a few needed to run programs, such as PATH and HOME")
+                )
+            .arg(Arg::with_name("env")
+                .long("env")
+                .value_name("KEY=VALUE")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("set an extra variable for the command. can be given more than once")
+                )
+            .arg(Arg::with_name("fork")
+                .long("fork")
+                .required(false)
+                .takes_value(false)
+                .help("run the command as a child process, passing on signals and its exit status, instead of replacing stscli with it")
+                )
+            )
+        .subcommand(SubCommand::with_name("shell")
+            .about("starts your shell with session tokens in the environment and a marker in the prompt. exit the shell to drop them")
+            .version("1.0")
+            .author("various")
+            )
+        .subcommand(SubCommand::with_name("serve")
+            .about("serves session tokens from a local endpoint, like the ECS container credentials endpoint, refreshing them before they expire")
+            .version("1.0")
+            .author("various")
+            .arg(Arg::with_name("port")
+                .long("port")
                 .required(false)
                 .takes_value(true)
-                .help("format to use when printing the variables. one of json, bash, fish or powershell. default bash")
+                .help("port to listen on at 127.0.0.1. default any free port")
                 )
+            .args(&output_args())
             )
-        .subcommand(SubCommand::with_name("exec")
-            .about("runs a command with session tokens injected into the environment")
+        .subcommand(SubCommand::with_name("assume-web-identity")
+            .about("exchanges an OpenID Connect token for session tokens and displays them, or runs a command with them")
             .version("1.0")
             .author("various")
+            .args(&output_args())
             .arg(Arg::with_name("command")
                 .long("command")
-                .required(true)
+                .required(false)
                 .takes_value(true)
                 .index(1)
                 .multiple(true)
-                .help("shell command to run")
+                .help("shell command to run instead of printing the variables")
+                )
+            )
+        .subcommand(SubCommand::with_name("assume-saml")
+            .about("exchanges a SAML assertion from an identity provider for session tokens and displays them")
+            .version("1.0")
+            .author("various")
+            .arg(Arg::with_name("assertion")
+                .required(false)
+                .takes_value(true)
+                .index(1)
+                .help("file containing the base64 encoded SAMLResponse, or - to read it from stdin. default stdin")
+                )
+            .args(&output_args())
+            )
+        .subcommand(SubCommand::with_name("imds")
+            .about("serves session tokens from an emulation of the EC2 instance metadata service (IMDSv2), refreshing them before they expire")
+            .version("1.0")
+            .author("various")
+            .arg(Arg::with_name("address")
+                .long("address")
+                .required(false)
+                .takes_value(true)
+                .help("address to listen on. default 127.0.0.1 on any free port")
+                )
+            .arg(Arg::with_name("allow_remote")
+                .long("allow-remote")
+                .required(false)
+                .requires("address")
+                .help("allow --address to be reachable from other hosts, which can then read the credentials")
+                )
+            .arg(Arg::with_name("role_name")
+                .long("role-name")
+                .required(false)
+                .takes_value(true)
+                .help("instance profile role name to serve the credentials under. default stscli")
+                )
+            .args(&output_args())
+            )
+        .subcommand(SubCommand::with_name("credential-process")
+            .about("prints session tokens in the format expected by credential_process in ~/.aws/config")
+            .version("1.0")
+            .author("various")
+            )
+        .subcommand(SubCommand::with_name("write")
+            .about("writes session tokens to a profile in the shared credentials file, for tools that only read that file")
+            .version("1.0")
+            .author("various")
+            .arg(Arg::with_name("target_profile")
+                .long("target-profile")
+                .value_name("PROFILE")
+                .required_unless("prune_expired")
+                .takes_value(true)
+                .help("name of the profile to create or update")
+                )
+            .arg(Arg::with_name("prune_expired")
+                .long("prune-expired")
+                .required(false)
+                .takes_value(false)
+                .help("remove profiles written by stscli whose credentials have expired")
+                )
+            )
+        .subcommand(SubCommand::with_name("whoami")
+            .about("shows the account, ARN and user id the session tokens belong to. use --profile all to check every profile")
+            .version("1.0")
+            .author("various")
+            .arg(Arg::with_name("format")
+                .long("format")
+                .short("f")
+                .required(false)
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .help("format to print the identity in. one of text or json. default text")
+                )
+            )
+        .subcommand(SubCommand::with_name("decode-message")
+            .about("decodes the encoded authorization failure message from an AccessDenied error")
+            .version("1.0")
+            .author("various")
+            .arg(Arg::with_name("message")
+                .required(false)
+                .takes_value(true)
+                .index(1)
+                .help("the encoded message, or the whole error it came in, or - to read it from stdin. default stdin")
                 )
             )
         .subcommand(SubCommand::with_name("list")
@@ -125,17 +361,77 @@ pub fn main() {
             .version("1.0")
             .author("various")
             )
+        .subcommand(SubCommand::with_name(output_file::DELETE_SUBCOMMAND)
+            .setting(AppSettings::Hidden)
+            .arg(Arg::with_name("at")
+                .long("at")
+                .required(true)
+                .takes_value(true)
+                )
+            .arg(Arg::with_name("file_id")
+                .long("file-id")
+                .required(false)
+                .takes_value(true)
+                )
+            .arg(Arg::with_name("path")
+                .required(true)
+                .index(1)
+                )
+            )
         .get_matches();
     
-    match run_subcommand(&matches) {
-        Err(err) => {
-            writeln!(&mut stderr, "Error: {}", err).unwrap();
-            process::exit(1);
-        },
-        _ => {}
+    if let Err(err) = run_subcommand(&matches) {
+        match err {
+            // the command has already reported its own errors
+            StsCliError::ChildExited(_) | StsCliError::ChildKilled(_) => {},
+            _ => writeln!(&mut stderr, "Error: {}", err).unwrap(),
+        }
+
+        process::exit(err.exit_code());
     }
 }
 
+/// The arguments of the subcommands that print the variables, see `print_output`.
+fn output_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("export")
+            .long("export")
+            .short("e")
+            .required(false)
+            .takes_value(false)
+            .help("print the variables for exporting to a shell"),
+        Arg::with_name("format")
+            .long("format")
+            .short("f")
+            .required(false)
+            .takes_value(true)
+            .possible_values(&OUTPUT_FORMATS)
+            .help("format to use when printing the variables. default bash"),
+        Arg::with_name("output_file")
+            .long("output-file")
+            .short("o")
+            .required(false)
+            .takes_value(true)
+            .help("write the variables to this file, readable only by you, instead of printing them"),
+        Arg::with_name("delete_after")
+            .long("delete-after")
+            .required(false)
+            .takes_value(true)
+            .requires("output_file")
+            .help("delete the output file after this long, in seconds or with an s, m or h suffix, e.g. 1h"),
+        Arg::with_name("secret_name")
+            .long("secret-name")
+            .required(false)
+            .takes_value(true)
+            .help("name of the Secret for --format k8s-secret. default aws-credentials"),
+        Arg::with_name("namespace")
+            .long("namespace")
+            .required(false)
+            .takes_value(true)
+            .help("namespace of the Secret for --format k8s-secret. default the namespace kubectl uses"),
+    ]
+}
+
 fn run_subcommand(matches: &ArgMatches) -> Result<()> {
     let config = try!(Config::new_for_matches(matches));
     debug!("config: {:?}", config);
@@ -143,25 +439,46 @@ fn run_subcommand(matches: &ArgMatches) -> Result<()> {
     match matches.subcommand() {
         ("get", Some(sub_matches)) => get_token(sub_matches, &config),
         ("exec", Some(sub_matches)) => exec_command(sub_matches, &config),
+        ("assume-web-identity", Some(sub_matches)) => {
+            if sub_matches.is_present("command") {
+                exec_command(sub_matches, &config)
+            } else {
+                get_token(sub_matches, &config)
+            }
+        },
+        ("assume-saml", Some(sub_matches)) => get_token(sub_matches, &config),
+        ("shell", Some(sub_matches)) => run_shell(sub_matches, &config),
+        ("serve", Some(sub_matches)) => serve_credentials(sub_matches, &config),
+        ("imds", Some(sub_matches)) => serve_imds(sub_matches, &config),
+        ("credential-process", Some(sub_matches)) => credential_process(sub_matches, &config),
+        ("write", Some(sub_matches)) => write_credentials(sub_matches, &config),
+        ("whoami", Some(sub_matches)) => whoami(sub_matches, &config),
+        ("decode-message", Some(sub_matches)) => decode_message(sub_matches, &config),
         ("list", Some(sub_matches)) => list_profiles(sub_matches, &config),
+        (output_file::DELETE_SUBCOMMAND, Some(sub_matches)) => delete_output_file(sub_matches),
         _ => Ok(())
     }
 }
 
+/// Gets just the credentials, for when what they are for doesn't matter.
 fn get_credentials(config: &Config) -> Result<rusoto_core::AwsCredentials> {
+    get_session(config).map(|session| session.credentials)
+}
+
+fn get_session(config: &Config) -> Result<Session> {
     let mut profile_provider = try!(ProfileProvider::new());
 
     if let Some(ref credentials_file_name) = config.credentials_file {
         profile_provider.set_file_path(credentials_file_name);
     }
 
-    if let Some(ref profile) = config.profile {
-        profile_provider.set_profile(&profile[..]);
-    }
-
-    let mut profile_provider = profile_provider.clone();
+    let profile = config.profile.clone().unwrap_or("default".to_owned());
     let mut region = Region::UsEast1;
-    let mut role_arn = None;
+    let mut profile_config = None;
+    let mut chain = aws_config::RoleChain {
+        credentials_profile: profile.clone(),
+        roles: vec![],
+    };
 
     if let Some(ref config_file_name) = config.config_file {
         let aws_config = try!(aws_config::Config::load_from_path(config_file_name));
@@ -170,80 +487,588 @@ fn get_credentials(config: &Config) -> Result<rusoto_core::AwsCredentials> {
             region = default_region.clone();
         }
 
-        if let Some(ref profile) = config.profile {
-            if let Some(ref profile_config) = aws_config.profiles.get(profile) {
-                if let Some(ref profile_region) = profile_config.region {
-                    region = profile_region.clone();
-                }
-
-                role_arn = profile_config.role_arn.clone();
+        profile_config = aws_config.profiles.get(&profile).cloned();
 
-                profile_provider.set_profile(profile_config.source_profile.clone().unwrap_or("default".to_owned()));
-            }
+        if let Some(ref profile_region) = profile_config.as_ref().and_then(|p| p.region.clone()) {
+            region = profile_region.clone();
         }
+
+        chain = try!(aws_config.resolve_role_chain(&profile));
     }
 
     if config.role.is_some() {
-        role_arn = config.role.clone();
+        // --role replaces the role of the selected profile, or is assumed using its credentials
+        match chain.roles.last_mut() {
+            Some(role_profile) => role_profile.role_arn = config.role.clone(),
+            None => {
+                let mut role_profile = aws_config::ConfigProfile::new(profile.clone());
+                role_profile.role_arn = config.role.clone();
+                chain.roles.push(role_profile);
+            }
+        }
     }
 
     if let Some(ref config_region) = config.region {
         region = config_region.clone();
     }
 
-    let base_provider = ChainProvider::with_profile_provider(profile_provider);
+    if config.saml {
+        return get_saml_credentials(config, region);
+    }
+
+    if web_identity::WebIdentity::is_selected(config, profile_config.as_ref()) {
+        let web_identity = try!(web_identity::WebIdentity::resolve(config, profile_config.as_ref()));
+        return get_web_identity_credentials(config, &profile, &web_identity, region);
+    }
+
+    let hops = chain.roles.len();
+    let mut requests: Vec<AssumeRoleRequest> = vec![];
+    let mut hop_params: Vec<Params> = vec![];
+
+    for (i, role_profile) in chain.roles.iter().enumerate() {
+        requests.push(assume_role_request(config, role_profile, i == 0, i == hops - 1));
+        hop_params.push(try!(assume_role_params(config, role_profile, i == hops - 1)));
+    }
+
+    assign_token_code(&mut requests, config.token_code.clone());
+
+    let mut session_token_request = session_token_request(config, profile_config.as_ref());
+
+    // GetSessionToken can't restrict the session, so without a role a federated user session is used instead
+    let federated = requests.is_empty() && !config.session_policy.is_empty();
+
+    if requests.is_empty() && !config.session_tags.is_empty() {
+        return Err(StsCliError::Error("session tags and source identities can only be used when assuming a role".to_owned()));
+    }
+
+    if federated && session_token_request.serial_number.is_some() {
+        return Err(StsCliError::Error("session policies need a role when using MFA, because GetFederationToken doesn't support MFA".to_owned()));
+    }
+
+    let session_kind = if federated {
+        duration::SessionKind::GetFederationToken
+    } else {
+        duration::SessionKind::GetSessionToken
+    };
+
+    try!(check_durations(&requests, session_kind, config.duration));
+
+    let cache_key = cache::CacheKey {
+        profile: profile.clone(),
+        role_arn: requests.last().map(|r| r.role_arn.clone()),
+        role_session_name: requests.last().map(|r| r.role_session_name.clone())
+            .or_else(|| config.name.clone())
+            .unwrap_or("stscli".to_owned()),
+        serial_number: requests.first().map(|r| r.serial_number.clone())
+            .unwrap_or(session_token_request.serial_number.clone()),
+        region: region.to_string(),
+        source: credentials_source(config, &chain.credentials_profile),
+        session_params: session_params(&config.session_policy.policy, hop_params.last().unwrap_or(&config.session_policy.params())),
+    };
+
+    // served credentials are refreshed with no one to enter a code, so the first hop's code is spent on an
+    // MFA session from GetSessionToken instead. It is cached, and the role is assumed with it from then on
+    let use_mfa_session = config.serve && requests.first().map(|r| r.serial_number.is_some()).unwrap_or(false);
+
+    with_cache(config, &cache_key, || {
+        if requests.is_empty() {
+            try!(resolve_token_code(config, &session_token_request.serial_number, &mut session_token_request.token_code));
+        }
+
+        for request in requests.iter_mut().skip(if use_mfa_session { 1 } else { 0 }) {
+            try!(resolve_token_code(config, &request.serial_number, &mut request.token_code));
+        }
+
+        profile_provider.set_profile(chain.credentials_profile.clone());
 
-    let sts_client = StsClient::new(try!(default_tls_client()), base_provider, region);
+        let base_provider = ChainProvider::with_profile_provider(profile_provider);
 
-    if let Some(role_arn) = role_arn {
-        let response = try!(sts_client.assume_role(&AssumeRoleRequest{
-            role_arn: role_arn.to_owned(),
-            role_session_name: config.name.clone().unwrap_or("stscli".to_owned()),
-            serial_number: config.serial_number.clone(),
-            token_code: config.token_code.clone(),
+        let session = if let Some((first_request, other_requests)) = requests.split_first() {
+            let mut session = if use_mfa_session {
+                let mfa_session = try!(get_mfa_session(config, &chain.credentials_profile, region.clone(), base_provider, first_request));
+                let request = AssumeRoleRequest {
+                    serial_number: None,
+                    token_code: None,
+                    ..first_request.clone()
+                };
+
+                try!(assume_role(FixedCredentialsProvider::new(mfa_session.credentials), region.clone(), &request, &hop_params[0]))
+            } else {
+                try!(assume_role(base_provider, region.clone(), first_request, &hop_params[0]))
+            };
+
+            for (request, params) in other_requests.iter().zip(&hop_params[1..]) {
+                session = try!(assume_role(FixedCredentialsProvider::new(session.credentials), region.clone(), request, params));
+            }
+
+            session
+        } else if federated {
+            try!(get_federation_token(base_provider, region, &GetFederationTokenRequest {
+                name: config.name.clone().unwrap_or("stscli".to_owned()),
+                duration_seconds: config.duration,
+                policy: config.session_policy.policy.clone(),
+            }, &config.session_policy.params()))
+        } else {
+            try!(get_session_token(base_provider, region, &session_token_request))
+        };
+
+        Ok(session)
+    })
+}
+
+/// Gets a session from GetSessionToken with the MFA device of the first hop, which roles that require MFA
+/// can then be assumed with without another code, until it expires.
+fn get_mfa_session<P>(config: &Config, credentials_profile: &str, region: Region, provider: P, first_request: &AssumeRoleRequest) -> Result<Session> where P: ProvideAwsCredentials {
+    let cache_key = cache::CacheKey {
+        profile: credentials_profile.to_owned(),
+        role_arn: None,
+        role_session_name: "stscli".to_owned(),
+        serial_number: first_request.serial_number.clone(),
+        region: region.to_string(),
+        source: credentials_source(config, credentials_profile),
+        session_params: BTreeMap::new(),
+    };
+
+    with_cache(config, &cache_key, || {
+        let mut request = GetSessionTokenRequest {
+            serial_number: first_request.serial_number.clone(),
+            token_code: first_request.token_code.clone(),
             ..Default::default()
-        }));
+        };
+
+        try!(resolve_token_code(config, &request.serial_number, &mut request.token_code));
+
+        get_session_token(provider, region, &request)
+    })
+}
 
-        let sts_creds = try!(response.credentials.ok_or(StsCliError::Error("STS AssumeRole did not return any credentials".to_owned())));
-        return Ok(try!(AwsCredentials::new_for_credentials(sts_creds)));
+/// Returns cached credentials for the key if there are any, otherwise fetches new ones and caches them.
+fn with_cache<F>(config: &Config, cache_key: &cache::CacheKey, fetch: F) -> Result<Session> where F: FnOnce() -> Result<Session> {
+    if config.no_cache {
+        return fetch();
     }
 
-    let response = try!(sts_client.get_session_token(&GetSessionTokenRequest {
-        ..Default::default()
-    }));
+    let cache = match cache::CredentialsCache::default_location() {
+        Ok(location) => cache::CredentialsCache::new(location, config.cache_margin),
+        Err(err) => {
+            warn!("not caching credentials: {}", err);
+            return fetch();
+        },
+    };
+
+    if !config.refresh {
+        match cache.get(cache_key) {
+            Ok(Some(session)) => {
+                debug!("using cached credentials for {:?}", cache_key);
+                return Ok(session);
+            },
+            Ok(None) => {},
+            Err(err) => warn!("ignoring credentials cache: {}", err),
+        }
+    }
+
+    let session = try!(fetch());
+
+    if let Err(err) = cache.put(cache_key, &session) {
+        warn!("failed to write credentials cache: {}", err);
+    }
+
+    Ok(session)
+}
+
+/// Where the long term credentials for a role chain come from. The AWS SDKs prefer the
+/// environment to the credentials file, so keys set there are used instead of the profile.
+fn credentials_source(config: &Config, credentials_profile: &str) -> String {
+    match env::var("AWS_ACCESS_KEY_ID") {
+        Ok(ref access_key_id) if !access_key_id.is_empty() => format!("environment:{}", access_key_id),
+        _ => match config.credentials_file {
+            Some(ref path) => format!("profile:{}:{}", credentials_profile, path.display()),
+            None => format!("profile:{}", credentials_profile),
+        },
+    }
+}
+
+fn get_web_identity_credentials(config: &Config, profile: &str, web_identity: &web_identity::WebIdentity, region: Region) -> Result<Session> {
+    if !config.session_tags.is_empty() {
+        return Err(StsCliError::Error("session tags and source identities for web identity sessions come from the token".to_owned()));
+    }
+
+    if let Some(duration_seconds) = config.duration {
+        try!(duration::check_duration(duration_seconds, duration::SessionKind::AssumeRole));
+    }
+
+    let extra_params = config.session_policy.params();
 
-    let sts_creds = try!(response.credentials.ok_or(StsCliError::Error("STS GetSessionTokenRequest did not return any credentials".to_owned())));
+    let cache_key = cache::CacheKey {
+        profile: profile.to_owned(),
+        role_arn: Some(web_identity.role_arn.clone()),
+        role_session_name: web_identity.role_session_name.clone(),
+        serial_number: None,
+        region: region.to_string(),
+        source: format!("web-identity:{}", web_identity.token_file.display()),
+        session_params: session_params(&config.session_policy.policy, &extra_params),
+    };
+
+    with_cache(config, &cache_key, || {
+        let token = try!(web_identity.read_token());
 
-    return Ok(try!(AwsCredentials::new_for_credentials(sts_creds)));
+        assume_role_with_web_identity(region, &AssumeRoleWithWebIdentityRequest {
+            role_arn: web_identity.role_arn.clone(),
+            role_session_name: web_identity.role_session_name.clone(),
+            web_identity_token: token,
+            duration_seconds: config.duration,
+            policy: config.session_policy.policy.clone(),
+            ..Default::default()
+        }, &extra_params)
+    })
 }
 
-fn get_output_format(args: &ArgMatches) -> OutputFormat {
-    let export = args.is_present("export");
+fn get_saml_credentials(config: &Config, region: Region) -> Result<Session> {
+    if !config.session_tags.is_empty() {
+        return Err(StsCliError::Error("session tags and source identities for SAML sessions come from the assertion".to_owned()));
+    }
 
-    match args.value_of("format") {
-        Some("json") => OutputFormat::Json,
-        Some("fish") => OutputFormat::Fish { export: export },
-        Some("powershell") => OutputFormat::Powershell { export: export },
-        _ => OutputFormat::Bash { export: export }
+    if let Some(duration_seconds) = config.duration {
+        try!(duration::check_duration(duration_seconds, duration::SessionKind::AssumeRole));
     }
+
+    let assertion = try!(saml::read_assertion(config.saml_assertion_file.as_ref().map(AsRef::as_ref)));
+    let roles = try!(saml::roles(&assertion));
+    let role = try!(saml::choose_role(&roles, config.role.as_ref().map(|s| &s[..])));
+
+    // assertions are single use and short lived, so these credentials aren't cached
+    assume_role_with_saml(region, &AssumeRoleWithSAMLRequest {
+        role_arn: role.role_arn,
+        principal_arn: role.principal_arn,
+        saml_assertion: assertion,
+        duration_seconds: config.duration,
+        policy: config.session_policy.policy.clone(),
+    }, &config.session_policy.params())
+}
+
+/// Asks for a token code if an MFA device is in use and no code was given.
+fn resolve_token_code(config: &Config, serial_number: &Option<String>, token_code: &mut Option<String>) -> Result<()> {
+    if token_code.is_none() {
+        if let Some(ref serial_number) = *serial_number {
+            let token_code_command = config.token_code_command.as_ref().map(|s| &s[..]);
+
+            if !config.interactive && token_code_command.is_none() {
+                return Err(StsCliError::TokenCodeNeeded(serial_number.clone()));
+            }
+            *token_code = Some(try!(mfa::get_token_code(serial_number, token_code_command)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Gives the token code to the first hop that uses an MFA device. AWS refuses a code that has
+/// already been used, so any later hops that need one ask for a new code.
+fn assign_token_code(requests: &mut [AssumeRoleRequest], token_code: Option<String>) {
+    if let Some(request) = requests.iter_mut().find(|r| r.serial_number.is_some()) {
+        request.token_code = token_code;
+    }
+}
+
+/// The request parameters, other than the role and MFA device, that change what a session is allowed to do.
+fn session_params(policy: &Option<String>, extra_params: &Params) -> BTreeMap<String, String> {
+    let mut params = BTreeMap::new();
+
+    if let Some(ref policy) = *policy {
+        params.insert("Policy".to_owned(), policy.clone());
+    }
+
+    for (k, v) in extra_params {
+        params.insert(k.clone(), v.clone().unwrap_or_default());
+    }
+
+    params
+}
+
+/// The parameters for one hop of a role chain that the STS client doesn't support.
+/// Tags and session policies given on the command line are for the session we hand out.
+fn assume_role_params(config: &Config, role_profile: &aws_config::ConfigProfile, last_hop: bool) -> Result<Params> {
+    if !last_hop {
+        try!(policy::SessionPolicy::default().check_packed_size(&role_profile.session_tags));
+        return Ok(role_profile.session_tags.params());
+    }
+
+    let session_tags = try!(role_profile.session_tags.merge(&config.session_tags));
+    try!(config.session_policy.check_packed_size(&session_tags));
+
+    let mut params = session_tags.params();
+    params.extend(config.session_policy.params());
+
+    Ok(params)
+}
+
+/// Checks requested durations before calling STS, which would only say that validation failed.
+fn check_durations(requests: &[AssumeRoleRequest], session_kind: duration::SessionKind, session_duration: Option<i64>) -> Result<()> {
+    if requests.is_empty() {
+        if let Some(duration_seconds) = session_duration {
+            try!(duration::check_duration(duration_seconds, session_kind));
+        }
+    }
+
+    for (i, request) in requests.iter().enumerate() {
+        if let Some(duration_seconds) = request.duration_seconds {
+            let kind = if i == 0 {
+                duration::SessionKind::AssumeRole
+            } else {
+                duration::SessionKind::ChainedAssumeRole
+            };
+
+            try!(duration::check_duration(duration_seconds, kind)
+                .map_err(|e| e.with_context(format!("can't assume {}", request.role_arn))));
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds the GetSessionToken request used when there is no role to assume. Command line options take precedence over the profile.
+fn session_token_request(config: &Config, profile_config: Option<&aws_config::ConfigProfile>) -> GetSessionTokenRequest {
+    let serial_number = config.serial_number.clone().or_else(|| profile_config.and_then(|p| p.mfa_serial.clone()));
+
+    GetSessionTokenRequest {
+        // a code is no use without a device
+        token_code: serial_number.as_ref().and(config.token_code.clone()),
+        serial_number: serial_number,
+        duration_seconds: config.duration,
+    }
+}
+
+/// Builds the request for one hop of a role chain. Command line options take precedence over the profile.
+fn assume_role_request(config: &Config, role_profile: &aws_config::ConfigProfile, first_hop: bool, last_hop: bool) -> AssumeRoleRequest {
+    // only the first hop is made with long term credentials, so that is where an MFA device given on the command line applies
+    let serial_number = if first_hop {
+        config.serial_number.clone().or_else(|| role_profile.mfa_serial.clone())
+    } else {
+        role_profile.mfa_serial.clone()
+    };
+
+    AssumeRoleRequest {
+        role_arn: role_profile.role_arn.clone().unwrap(),
+        role_session_name: config.name.clone()
+            .or_else(|| role_profile.role_session_name.clone())
+            .unwrap_or("stscli".to_owned()),
+        serial_number: serial_number,
+        external_id: if last_hop {
+            config.external_id.clone().or(role_profile.external_id.clone())
+        } else {
+            role_profile.external_id.clone()
+        },
+        // --duration is for the session we hand out, not the intermediate ones
+        duration_seconds: if last_hop {
+            config.duration.or(role_profile.duration_seconds)
+        } else {
+            role_profile.duration_seconds
+        },
+        policy: if last_hop {
+            config.session_policy.policy.clone()
+        } else {
+            None
+        },
+        ..Default::default()
+    }
+}
+
+fn assume_role<P>(provider: P, region: Region, request: &AssumeRoleRequest, extra_params: &Params) -> Result<Session> where P: ProvideAwsCredentials {
+    debug!("assuming role {}", request.role_arn);
+
+    let credentials = try!(provider.credentials());
+    let dispatcher = ExtraParamsDispatcher::new(try!(default_tls_client()), extra_params.clone(), credentials.clone());
+    let sts_client = StsClient::new(dispatcher, FixedCredentialsProvider::new(credentials), region);
+
+    let response = try!(sts_client.assume_role(request));
+
+    Session::from_sts("AssumeRole", response.credentials, response.assumed_role_user, Some(request.role_arn.clone()))
+}
+
+fn get_session_token<P>(provider: P, region: Region, request: &GetSessionTokenRequest) -> Result<Session> where P: ProvideAwsCredentials {
+    let sts_client = StsClient::new(try!(default_tls_client()), provider, region);
+
+    let response = try!(sts_client.get_session_token(request));
+
+    Session::from_sts("GetSessionToken", response.credentials, None, None)
+}
+
+fn assume_role_with_web_identity(region: Region, request: &AssumeRoleWithWebIdentityRequest, extra_params: &Params) -> Result<Session> {
+    debug!("assuming role {} with web identity", request.role_arn);
+
+    // the token authenticates the request, so it isn't signed
+    let dispatcher = ExtraParamsDispatcher::anonymous(try!(default_tls_client()), extra_params.clone());
+    let sts_client = StsClient::new(dispatcher, FixedCredentialsProvider::anonymous(), region);
+
+    let response = try!(sts_client.assume_role_with_web_identity(request));
+
+    Session::from_sts("AssumeRoleWithWebIdentity", response.credentials, response.assumed_role_user, Some(request.role_arn.clone()))
+}
+
+fn assume_role_with_saml(region: Region, request: &AssumeRoleWithSAMLRequest, extra_params: &Params) -> Result<Session> {
+    debug!("assuming role {} with SAML provider {}", request.role_arn, request.principal_arn);
+
+    // the assertion authenticates the request, so it isn't signed
+    let dispatcher = ExtraParamsDispatcher::anonymous(try!(default_tls_client()), extra_params.clone());
+    let sts_client = StsClient::new(dispatcher, FixedCredentialsProvider::anonymous(), region);
+
+    let response = try!(sts_client.assume_role_with_saml(request));
+
+    Session::from_sts("AssumeRoleWithSAML", response.credentials, response.assumed_role_user, Some(request.role_arn.clone()))
+}
+
+fn get_federation_token<P>(provider: P, region: Region, request: &GetFederationTokenRequest, extra_params: &Params) -> Result<Session> where P: ProvideAwsCredentials {
+    let credentials = try!(provider.credentials());
+    let dispatcher = ExtraParamsDispatcher::new(try!(default_tls_client()), extra_params.clone(), credentials.clone());
+    let sts_client = StsClient::new(dispatcher, FixedCredentialsProvider::new(credentials), region);
+
+    let response = try!(sts_client.get_federation_token(request));
+
+    Session::from_sts("GetFederationToken", response.credentials, None, None)
+}
+
+fn get_output_format(args: &ArgMatches) -> Result<OutputFormat> {
+    OutputFormat::from_name(args.value_of("format").unwrap_or("bash"), args.is_present("export"))
 }
 
 fn get_token(args: &ArgMatches, config: &Config) -> Result<()> {
+    let session = try!(get_session(config));
+    let output_format = try!(get_output_format(args));
+
+    let vars = try!(get_vars(args, config, &session));
+
+    print_output(args, config, &vars, output_format)
+}
+
+fn print_output(args: &ArgMatches, _config: &Config, vars: &HashMap<String, String>, output_format: OutputFormat) -> Result<()> {
+    let text = match output_format {
+        OutputFormat::K8sSecret => {
+            let name = args.value_of("secret_name").unwrap_or(k8s::DEFAULT_SECRET_NAME);
+            try!(k8s::secret_manifest(vars, name, args.value_of("namespace")))
+        },
+        format => try!(format_vars(vars, format)),
+    };
+
+    match args.value_of("output_file") {
+        Some(output_file) => {
+            let delete_after = match args.value_of("delete_after") {
+                Some(delete_after) => Some(try!(parse_delete_after(delete_after))),
+                None => None,
+            };
+
+            output_file::write(path::Path::new(output_file), &text, delete_after)
+        },
+        None => {
+            print!("{}", text);
+            Ok(())
+        },
+    }
+}
+
+fn parse_delete_after(delete_after: &str) -> Result<i64> {
+    match try!(duration::parse_duration(delete_after)) {
+        seconds if seconds > 0 => Ok(seconds),
+        _ => Err(StsCliError::Error(format!("invalid --delete-after {}: it must be more than 0", delete_after))),
+    }
+}
+
+fn delete_output_file(args: &ArgMatches) -> Result<()> {
+    let delete_at = try!(args.value_of("at").unwrap().parse::<i64>()
+        .map_err(|_| StsCliError::Error("invalid --at".to_owned())));
+    let file_id = match args.value_of("file_id") {
+        Some(id) => Some(try!(id.parse::<u64>().map_err(|_| StsCliError::Error("invalid --file-id".to_owned())))),
+        None => None,
+    };
+
+    output_file::delete_when_due(path::Path::new(args.value_of("path").unwrap()), delete_at, file_id)
+}
+
+/// Credentials for a server to hand out, which are fetched again the same way when they are about to expire.
+///
+/// Refreshing happens on a server thread while the terminal may belong to a command, so it never asks for
+/// an MFA code, and the code given on the command line has already been used.
+fn refreshing_credentials(config: &Config, creds: rusoto_core::AwsCredentials) -> serve::RefreshingCredentials {
+    let mut refresh_config = config.clone();
+    refresh_config.token_code = None;
+    refresh_config.refresh = false;
+    refresh_config.interactive = false;
+
+    serve::RefreshingCredentials::new(&refresh_config, refresh_credentials, Some(creds))
+}
+
+fn refresh_credentials(config: &Config) -> Result<rusoto_core::AwsCredentials> {
+    if config.saml {
+        return Err(StsCliError::Error("SAML assertions can only be used once, so these credentials can't be refreshed".to_owned()));
+    }
+
+    get_credentials(config)
+}
+
+fn start_credentials_server(config: &Config, port: u16, creds: rusoto_core::AwsCredentials) -> Result<serve::CredentialsServer> {
+    serve::CredentialsServer::start(port, refreshing_credentials(config, creds))
+}
+
+fn serve_credentials(args: &ArgMatches, config: &Config) -> Result<()> {
+    let port = match args.value_of("port") {
+        Some(port) => try!(port.parse::<u16>().map_err(|_| StsCliError::Error(format!("invalid port {}", port)))),
+        None => 0,
+    };
+
+    // get the first credentials now, so any MFA prompt happens before the endpoint is handed out
+    let creds = try!(get_credentials(config));
+    let server = try!(start_credentials_server(config, port, creds));
+
+    info!("serving credentials at {}", server.url);
+    try!(print_output(args, config, &server.vars(), try!(get_output_format(args))));
+
+    server.wait()
+}
+
+fn serve_imds(args: &ArgMatches, config: &Config) -> Result<()> {
+    let address = args.value_of("address").unwrap_or("127.0.0.1:0");
+    let role_name = args.value_of("role_name").unwrap_or("stscli").to_owned();
+
     let creds = try!(get_credentials(config));
-    let output_format = get_output_format(args);
+    let server = try!(imds::ImdsServer::start(address, args.is_present("allow_remote"), role_name, refreshing_credentials(config, creds)));
 
-    let vars = try!(get_vars(args, config, &creds));
+    info!("serving instance metadata at {}", server.url);
+    try!(print_output(args, config, &server.vars(), try!(get_output_format(args))));
 
-    match output_format {
-        OutputFormat::Json => { try!(print_vars_json(args, config, &vars)); },
-        format => { try!(print_vars(args, config, &vars, format)); }
+    server.wait()
+}
+
+fn credential_process(_args: &ArgMatches, config: &Config) -> Result<()> {
+    let creds = try!(get_credentials(config));
+
+    print_credential_process(&creds)
+}
+
+fn write_credentials(args: &ArgMatches, config: &Config) -> Result<()> {
+    let default_credentials_path = try!(get_default_credentials_path());
+    let credentials_path = config.credentials_file.as_ref().unwrap_or(&default_credentials_path);
+
+    let creds = match args.value_of("target_profile") {
+        Some(_) => Some(try!(get_credentials(config))),
+        None => None,
     };
 
-    Ok(())
+    let mut credentials_file = try!(credentials_file::CredentialsFile::load(credentials_path));
+
+    if let (Some(target_profile), Some(creds)) = (args.value_of("target_profile"), creds) {
+        try!(credentials_file.set_credentials(target_profile, &creds));
+    }
+
+    if args.is_present("prune_expired") {
+        for profile in credentials_file.prune_expired(chrono::Utc::now()) {
+            info!("removed expired profile {}", profile);
+        }
+    }
+
+    credentials_file.save(credentials_path)
 }
 
 fn exec_command(matches: &ArgMatches, config: &Config) -> Result<()> {
-    let creds = try!(get_credentials(config));
+    let session = try!(get_session(config));
 
     let command_line: Vec<&str> = matches.values_of("command").unwrap().collect();
     
@@ -251,13 +1076,84 @@ fn exec_command(matches: &ArgMatches, config: &Config) -> Result<()> {
     let command_name = command_line_iter.next().unwrap();
     let args: Vec<&str> = command_line_iter.collect();
 
-    let env = try!(get_vars(matches, config, &creds));
+    let mut env = try!(get_vars(matches, config, &session));
+
+    // the SDKs prefer keys in the environment, so only the endpoint is passed on.
+    // The expiration goes too, since the endpoint's credentials are refreshed
+    let _server = if matches.is_present("serve") {
+        for key in &["AWS_ACCESS_KEY_ID", "AWS_SECRET_ACCESS_KEY", "AWS_SESSION_TOKEN", "AWS_SECURITY_TOKEN",
+                     "AWS_CREDENTIAL_EXPIRATION", "AWS_SESSION_EXPIRATION"] {
+            env.remove(*key);
+        }
+
+        let server = try!(start_credentials_server(config, 0, session.credentials));
+        env.extend(server.vars());
+        Some(server)
+    } else {
+        None
+    };
+
+    if let Some(vars) = matches.values_of("env") {
+        try!(add_env_vars(&mut env, vars));
+    }
+
+    // by default only variables that would mix other credentials with the session's are removed
+    let inherit = if matches.is_present("isolate") { Inherit::Isolated } else { Inherit::Scrubbed };
 
-    spawn_command(OsString::from(command_name).as_os_str(), &args[..], &env)
+    // the endpoint is served from this process, so it has to stay around
+    let fork = matches.is_present("fork") || matches.is_present("serve");
+
+    spawn_command(OsString::from(command_name).as_os_str(), &args[..], &env, inherit, fork)
 }
 
-fn get_vars(_matches: &ArgMatches, config: &Config, creds: &rusoto_core::AwsCredentials) -> Result<HashMap<String, String>> {
+fn run_shell(args: &ArgMatches, config: &Config) -> Result<()> {
+    let session = try!(get_session(config));
+    let mut env = try!(get_vars(args, config, &session));
+    let profile = config.profile.clone().unwrap_or("default".to_owned());
+
+    if let Ok(active_profile) = env::var(shell::ACTIVE_VAR) {
+        try!(writeln!(std::io::stderr(), "warning: this is already an stscli shell for {}. exit the new shell to go back to it", active_profile));
+    }
+
+    env.insert(shell::ACTIVE_VAR.to_owned(), profile);
+
+    let program = env::var_os("SHELL").map(path::PathBuf::from).unwrap_or(path::PathBuf::from("/bin/sh"));
+    let shell = try!(shell::Shell::new(program, session.credentials.expires_at().timestamp()));
+
+    if shell.kind == shell::ShellKind::Other {
+        try!(writeln!(std::io::stderr(), "warning: can't add a marker to the prompt of {}", shell.program.display()));
+    }
+
+    env.extend(shell.env.clone());
+
+    // forked, so the files that set the prompt can be removed when the shell exits
+    let result = spawn_command(shell.program.as_os_str(), &shell.args, &env, Inherit::Scrubbed, true);
+    drop(shell);
+
+    result
+}
+
+/// Parses a variable given as `KEY=VALUE`. The value may contain `=`.
+/// Adds the variables given with `--env`, which take precedence over the session's.
+fn add_env_vars<'a, I>(env: &mut HashMap<String, String>, vars: I) -> Result<()> where I: IntoIterator<Item = &'a str> {
+    for var in vars {
+        let (key, value) = try!(parse_env_var(var));
+        env.insert(key, value);
+    }
+
+    Ok(())
+}
+
+fn parse_env_var(var: &str) -> Result<(String, String)> {
+    match var.find('=') {
+        Some(i) if i > 0 => Ok((var[..i].to_owned(), var[i + 1..].to_owned())),
+        _ => Err(StsCliError::Error(format!("variable {} should be written as KEY=VALUE", var))),
+    }
+}
+
+fn get_vars(_matches: &ArgMatches, config: &Config, session: &Session) -> Result<HashMap<String, String>> {
     let mut env: HashMap<String, String> = HashMap::new();
+    let creds = &session.credentials;
 
     env.insert("AWS_ACCESS_KEY_ID".to_owned(), creds.aws_access_key_id().to_owned());
     env.insert("AWS_SECRET_ACCESS_KEY".to_owned(), creds.aws_secret_access_key().to_owned());
@@ -270,6 +1166,18 @@ fn get_vars(_matches: &ArgMatches, config: &Config, creds: &rusoto_core::AwsCred
         env.insert("AWS_DEFAULT_REGION".to_owned(), region.to_string());
     }
 
+    env.insert("AWS_CREDENTIAL_EXPIRATION".to_owned(), session.expiration());
+    env.insert("AWS_SESSION_EXPIRATION".to_owned(), session.expiration());
+    env.insert("STSCLI_PROFILE".to_owned(), config.profile.clone().unwrap_or("default".to_owned()));
+
+    if let Some(ref role_arn) = session.role_arn {
+        env.insert("STSCLI_ROLE_ARN".to_owned(), role_arn.to_owned());
+    }
+
+    if let Some(ref assumed_role_arn) = session.assumed_role_arn {
+        env.insert("STSCLI_ASSUMED_ROLE_ARN".to_owned(), assumed_role_arn.to_owned());
+    }
+
     Ok(env)
 }
 
@@ -309,3 +1217,351 @@ fn list_profiles(_matches: &ArgMatches, config: &Config) -> Result<()> {
 
     Ok(())
 }
+
+/// Every profile in the credentials and config files, including `default`, sorted and without duplicates.
+fn profile_names(config: &Config) -> Result<Vec<String>> {
+    let mut names: Vec<String> = vec![];
+
+    let default_credentials_path = try!(get_default_credentials_path());
+    let credentials_file = config.credentials_file.as_ref().unwrap_or(&default_credentials_path);
+    let default_config_path = try!(get_default_config_path());
+    let config_file = config.config_file.as_ref().unwrap_or(&default_config_path);
+
+    for file in &[credentials_file, config_file] {
+        let profiles = try!(aws_config::Config::load_from_path(file));
+
+        if profiles.has_default {
+            names.push("default".to_owned());
+        }
+        names.extend(profiles.profiles.keys().cloned());
+    }
+
+    names.sort();
+    names.dedup();
+
+    Ok(names)
+}
+
+fn whoami(args: &ArgMatches, config: &Config) -> Result<()> {
+    let json = args.value_of("format") == Some("json");
+
+    if config.profile.as_ref().map(|p| &p[..]) != Some("all") {
+        let identity = try!(get_identity(config));
+        return print_identity(&identity, json);
+    }
+
+    let mut identities = vec![];
+
+    for profile in try!(profile_names(config)) {
+        let mut profile_config = config.clone();
+        profile_config.profile = Some(profile.clone());
+        // asking for a code for every profile would be a chore, so those that need one are skipped
+        profile_config.interactive = false;
+
+        let mut identity = match get_identity(&profile_config) {
+            Ok(identity) => identity,
+            Err(err @ StsCliError::TokenCodeNeeded(_)) => Identity {
+                skipped: Some(err.to_string()),
+                ..Default::default()
+            },
+            Err(err) => Identity {
+                error: Some(err.to_string()),
+                ..Default::default()
+            },
+        };
+        identity.profile = Some(profile);
+
+        identities.push(identity);
+    }
+
+    try!(print_identities(&identities, json));
+
+    let failed = identities.iter().filter(|i| i.error.is_some()).count();
+    if failed > 0 {
+        return Err(StsCliError::Error(format!("{} of {} profiles failed", failed, identities.len())));
+    }
+
+    Ok(())
+}
+
+fn decode_message(args: &ArgMatches, config: &Config) -> Result<()> {
+    let input = try!(decode::read_message(args.value_of("message")));
+    let encoded_message = try!(decode::extract_encoded_message(&input));
+
+    let creds = try!(get_credentials(config));
+    let region = config.region.clone().unwrap_or(Region::UsEast1);
+
+    let sts_client = StsClient::new(try!(default_tls_client()), FixedCredentialsProvider::new(creds), region);
+    let response = try!(sts_client.decode_authorization_message(&DecodeAuthorizationMessageRequest {
+        encoded_message: encoded_message,
+    }));
+
+    let decoded_message = try!(response.decoded_message.ok_or(StsCliError::Error("STS DecodeAuthorizationMessage did not return a message".to_owned())));
+
+    decode::print_decoded_message(&decoded_message, decode::stdout_is_terminal())
+}
+
+fn get_identity(config: &Config) -> Result<Identity> {
+    let creds = try!(get_credentials(config));
+    let region = try!(get_region(config));
+
+    let sts_client = StsClient::new(try!(default_tls_client()), FixedCredentialsProvider::new(creds), region);
+    let response = try!(sts_client.get_caller_identity(&GetCallerIdentityRequest));
+
+    Ok(Identity {
+        account: response.account,
+        arn: response.arn,
+        user_id: response.user_id,
+        ..Default::default()
+    })
+}
+
+/// The region from `--region`, the profile or the `default` profile, as `get_session` picks it.
+fn get_region(config: &Config) -> Result<Region> {
+    if let Some(ref region) = config.region {
+        return Ok(region.clone());
+    }
+
+    if let Some(ref config_file_name) = config.config_file {
+        let aws_config = try!(aws_config::Config::load_from_path(config_file_name));
+        let profile = config.profile.clone().unwrap_or("default".to_owned());
+
+        if let Some(region) = aws_config.profiles.get(&profile).and_then(|p| p.region.clone()).or(aws_config.default_region) {
+            return Ok(region);
+        }
+    }
+
+    Ok(Region::UsEast1)
+}
+
+#[cfg(test)]
+mod tests {
+    use rusoto_sts::AssumeRoleRequest;
+    use std::fs;
+    use std::slice;
+    use super::*;
+
+    fn request(role: &str, serial_number: Option<&str>) -> AssumeRoleRequest {
+        AssumeRoleRequest {
+            role_arn: role.to_owned(),
+            serial_number: serial_number.map(|s| s.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn token_code_is_only_used_once() {
+        let mut requests = vec![
+            request("hub", None),
+            request("prod", Some("arn:aws:iam::111111111111:mfa/user")),
+            request("admin", Some("arn:aws:iam::111111111111:mfa/user")),
+        ];
+
+        assign_token_code(&mut requests, Some("123456".to_owned()));
+
+        assert_eq!(requests[0].token_code, None);
+        assert_eq!(requests[1].token_code, Some("123456".to_owned()));
+        assert_eq!(requests[2].token_code, None);
+    }
+
+    #[test]
+    fn refreshes_only_use_the_token_code_command() {
+        let config = Config::default();
+        let serial_number = Some("arn:aws:iam::111111111111:mfa/user".to_owned());
+        let mut token_code = None;
+        match resolve_token_code(&Config { interactive: false, ..config.clone() }, &serial_number, &mut token_code) {
+            Err(err @ StsCliError::TokenCodeNeeded(_)) => assert!(err.to_string().contains("--token-code-command")),
+            result => panic!("expected TokenCodeNeeded, got {:?}", result),
+        }
+
+        let mut token_code = None;
+        resolve_token_code(&Config { interactive: false, token_code_command: Some("echo 654321".to_owned()), ..config }, &serial_number, &mut token_code).unwrap();
+        assert_eq!(token_code, Some("654321".to_owned()));
+    }
+
+    #[test]
+    fn saml_credentials_are_not_refreshed() {
+        assert!(refresh_credentials(&Config { saml: true, ..Default::default() }).is_err());
+    }
+
+    fn var_names(vars: &HashMap<String, String>) -> Vec<&str> {
+        let mut names: Vec<&str> = vars.keys().map(|k| &k[..]).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn vars_describe_the_session() {
+        use chrono::{DateTime, Utc};
+
+        let expires_at = "2030-01-02T03:04:05Z".parse::<DateTime<Utc>>().unwrap();
+        let config = Config {
+            profile: Some("dev".to_owned()),
+            region: Some(Region::EuWest1),
+            ..Default::default()
+        };
+        let session = Session {
+            credentials: AwsCredentials::new("ASIAEXAMPLE", "secret", Some("token".to_owned()), expires_at),
+            role_arn: Some("arn:aws:iam::111111111111:role/dev".to_owned()),
+            assumed_role_arn: Some("arn:aws:sts::111111111111:assumed-role/dev/stscli".to_owned()),
+        };
+
+        let vars = get_vars(&ArgMatches::default(), &config, &session).unwrap();
+
+        assert_eq!(var_names(&vars), vec![
+            "AWS_ACCESS_KEY_ID", "AWS_CREDENTIAL_EXPIRATION", "AWS_DEFAULT_REGION", "AWS_SECRET_ACCESS_KEY",
+            "AWS_SECURITY_TOKEN", "AWS_SESSION_EXPIRATION", "AWS_SESSION_TOKEN",
+            "STSCLI_ASSUMED_ROLE_ARN", "STSCLI_PROFILE", "STSCLI_ROLE_ARN",
+        ]);
+        assert_eq!(vars["AWS_CREDENTIAL_EXPIRATION"], "2030-01-02T03:04:05Z");
+        assert_eq!(vars["AWS_SESSION_EXPIRATION"], vars["AWS_CREDENTIAL_EXPIRATION"]);
+        assert_eq!(DateTime::parse_from_rfc3339(&vars["AWS_CREDENTIAL_EXPIRATION"]).unwrap(), expires_at);
+        assert_eq!(vars["AWS_SESSION_TOKEN"], "token");
+        assert_eq!(vars["STSCLI_PROFILE"], "dev");
+        assert_eq!(vars["STSCLI_ROLE_ARN"], "arn:aws:iam::111111111111:role/dev");
+        assert_eq!(vars["STSCLI_ASSUMED_ROLE_ARN"], "arn:aws:sts::111111111111:assumed-role/dev/stscli");
+
+        // long term keys, e.g. from --profile with no role or MFA
+        let session = Session::new(AwsCredentials::new("AKIAEXAMPLE", "secret", None, expires_at));
+
+        let vars = get_vars(&ArgMatches::default(), &Config::default(), &session).unwrap();
+
+        assert_eq!(var_names(&vars), vec![
+            "AWS_ACCESS_KEY_ID", "AWS_CREDENTIAL_EXPIRATION", "AWS_SECRET_ACCESS_KEY", "AWS_SESSION_EXPIRATION", "STSCLI_PROFILE",
+        ]);
+        assert_eq!(vars["AWS_CREDENTIAL_EXPIRATION"], "2030-01-02T03:04:05Z");
+        assert_eq!(vars["STSCLI_PROFILE"], "default");
+    }
+
+    #[test]
+    fn env_vars_are_split_at_the_first_equals_sign() {
+        assert_eq!(parse_env_var("K=V=W").unwrap(), ("K".to_owned(), "V=W".to_owned()));
+        assert_eq!(parse_env_var("K=").unwrap(), ("K".to_owned(), "".to_owned()));
+        assert!(parse_env_var("=V").is_err());
+        assert!(parse_env_var("KV").is_err());
+    }
+
+    #[test]
+    fn given_env_vars_override_the_sessions() {
+        let mut env = HashMap::new();
+        env.insert("AWS_DEFAULT_REGION".to_owned(), "us-east-1".to_owned());
+
+        add_env_vars(&mut env, vec!["AWS_DEFAULT_REGION=eu-west-1", "EXTRA=1"]).unwrap();
+
+        assert_eq!(env.get("AWS_DEFAULT_REGION").map(|s| &s[..]), Some("eu-west-1"));
+        assert_eq!(env.get("EXTRA").map(|s| &s[..]), Some("1"));
+        assert!(add_env_vars(&mut env, vec!["=1"]).is_err());
+    }
+
+    /// A config and credentials file in a new temporary directory.
+    fn aws_files(name: &str, config_file: &str, credentials_file: &str) -> Config {
+        let dir = env::temp_dir().join(format!("stscli-main-test-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        files::write_private_file_atomic(&dir.join("config"), config_file.as_bytes()).unwrap();
+        files::write_private_file_atomic(&dir.join("credentials"), credentials_file.as_bytes()).unwrap();
+
+        Config {
+            config_file: Some(dir.join("config")),
+            credentials_file: Some(dir.join("credentials")),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn profile_names_include_default() {
+        let config = aws_files("names",
+            "[default]\nregion = eu-west-1\n[profile admin]\nrole_arn = arn:aws:iam::111111111111:role/admin\n[profile dev]\n",
+            "[default]\naws_access_key_id = A\n[dev]\naws_access_key_id = B\n");
+
+        assert_eq!(profile_names(&config).unwrap(), vec!["admin", "default", "dev"]);
+    }
+
+    #[test]
+    fn region_comes_from_the_profile() {
+        let config = aws_files("region",
+            "[default]\nregion = eu-west-1\n[profile admin]\nregion = eu-central-1\n[profile dev]\n",
+            "");
+
+        assert_eq!(get_region(&config).unwrap(), Region::EuWest1);
+        assert_eq!(get_region(&Config { profile: Some("admin".to_owned()), ..config.clone() }).unwrap(), Region::EuCentral1);
+        assert_eq!(get_region(&Config { profile: Some("dev".to_owned()), ..config.clone() }).unwrap(), Region::EuWest1);
+        assert_eq!(get_region(&Config { profile: Some("admin".to_owned()), region: Some(Region::UsWest2), ..config }).unwrap(), Region::UsWest2);
+    }
+
+    fn role_profile(name: &str, duration_seconds: Option<i64>) -> aws_config::ConfigProfile {
+        let mut profile = aws_config::ConfigProfile::new(name);
+        profile.role_arn = Some(format!("arn:aws:iam::111111111111:role/{}", name));
+        profile.mfa_serial = Some(format!("arn:aws:iam::111111111111:mfa/{}", name));
+        profile.external_id = Some(format!("{}-external-id", name));
+        profile.duration_seconds = duration_seconds;
+        profile
+    }
+
+    #[test]
+    fn session_token_requests_use_the_command_line_and_profile() {
+        let profile = role_profile("user", None);
+        let config = Config {
+            duration: Some(7200),
+            token_code: Some("123456".to_owned()),
+            ..Default::default()
+        };
+
+        let request = session_token_request(&config, Some(&profile));
+        assert_eq!(request.serial_number, Some("arn:aws:iam::111111111111:mfa/user".to_owned()));
+        assert_eq!(request.token_code, Some("123456".to_owned()));
+        assert_eq!(request.duration_seconds, Some(7200));
+
+        let request = session_token_request(&Config {
+            serial_number: Some("arn:aws:iam::111111111111:mfa/cli".to_owned()),
+            ..config.clone()
+        }, Some(&profile));
+        assert_eq!(request.serial_number, Some("arn:aws:iam::111111111111:mfa/cli".to_owned()));
+        assert_eq!(request.token_code, Some("123456".to_owned()));
+
+        let request = session_token_request(&config, None);
+        assert_eq!(request.serial_number, None);
+        assert_eq!(request.token_code, None);
+        assert_eq!(request.duration_seconds, Some(7200));
+
+        let request = session_token_request(&Config::default(), Some(&profile));
+        assert_eq!(request.token_code, None);
+        assert_eq!(request.duration_seconds, None);
+    }
+
+    #[test]
+    fn command_line_options_apply_to_the_right_hop() {
+        let config = Config {
+            serial_number: Some("arn:aws:iam::111111111111:mfa/cli".to_owned()),
+            external_id: Some("cli-external-id".to_owned()),
+            duration: Some(900),
+            name: Some("cli-session".to_owned()),
+            ..Default::default()
+        };
+
+        let first = assume_role_request(&config, &role_profile("hub", Some(3600)), true, false);
+        assert_eq!(first.serial_number, Some("arn:aws:iam::111111111111:mfa/cli".to_owned()));
+        assert_eq!(first.external_id, Some("hub-external-id".to_owned()));
+        assert_eq!(first.duration_seconds, Some(3600));
+        assert_eq!(first.role_session_name, "cli-session");
+
+        let last = assume_role_request(&config, &role_profile("prod", Some(3600)), false, true);
+        assert_eq!(last.serial_number, Some("arn:aws:iam::111111111111:mfa/prod".to_owned()));
+        assert_eq!(last.external_id, Some("cli-external-id".to_owned()));
+        assert_eq!(last.duration_seconds, Some(900));
+    }
+
+    #[test]
+    fn chained_roles_have_shorter_durations() {
+        let config = Config::default();
+        let hub = assume_role_request(&config, &role_profile("hub", Some(43200)), true, false);
+        let prod = assume_role_request(&config, &role_profile("prod", Some(3600)), false, true);
+        let long_prod = assume_role_request(&config, &role_profile("prod", Some(7200)), false, true);
+
+        assert!(check_durations(&[hub.clone(), prod], duration::SessionKind::GetSessionToken, None).is_ok());
+        assert!(check_durations(slice::from_ref(&hub), duration::SessionKind::GetSessionToken, None).is_ok());
+        assert!(check_durations(&[hub, long_prod], duration::SessionKind::GetSessionToken, None).is_err());
+
+        assert!(check_durations(&[], duration::SessionKind::GetSessionToken, Some(129600)).is_ok());
+        assert!(check_durations(&[], duration::SessionKind::GetFederationToken, Some(129601)).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use result::*;
+
+const TOKEN_CODE_LENGTH: usize = 6;
+const PROMPT_ATTEMPTS: usize = 3;
+
+/// Checks that a token code looks like the six digits shown by an MFA device.
+pub fn validate_token_code(code: &str) -> Result<()> {
+    if code.len() == TOKEN_CODE_LENGTH && code.chars().all(|c| c.is_ascii_digit()) {
+        Ok(())
+    } else {
+        Err(StsCliError::Error(format!("MFA token code must be {} digits", TOKEN_CODE_LENGTH)))
+    }
+}
+
+/// Gets a token code for an MFA device from the command if given, otherwise by asking on the terminal.
+pub fn get_token_code(serial_number: &str, token_code_command: Option<&str>) -> Result<String> {
+    match token_code_command {
+        Some(command) => run_token_code_command(command, serial_number),
+        None => prompt_token_code(serial_number),
+    }
+}
+
+fn run_token_code_command(command: &str, serial_number: &str) -> Result<String> {
+    let output = try!(shell_command(command)
+        .env("STSCLI_MFA_SERIAL", serial_number)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output());
+
+    if !output.status.success() {
+        return Err(StsCliError::Error(format!("token code command `{}` failed: {}", command, output.status)));
+    }
+
+    // the output usually ends with a line break, which isn't part of the code
+    let code = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+
+    if code.is_empty() {
+        return Err(StsCliError::Error(format!("token code command `{}` printed nothing", command)));
+    }
+
+    try!(validate_token_code(&code));
+
+    Ok(code)
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Asks for the code on the controlling terminal rather than stdout, so `eval $(stscli get)` still works.
+#[cfg(unix)]
+fn prompt_token_code(serial_number: &str) -> Result<String> {
+    use std::fs::OpenOptions;
+
+    let mut tty = try!(OpenOptions::new().read(true).write(true).open("/dev/tty")
+        .map_err(|e| StsCliError::Error(format!(
+            "an MFA token code is needed for {} but there is no terminal to ask for it ({}). Use --token-code or --token-code-command",
+            serial_number, e))));
+
+    for _ in 0..PROMPT_ATTEMPTS {
+        try!(write!(tty, "MFA token code for {}: ", serial_number));
+        try!(tty.flush());
+
+        let code = try!(tty::read_masked(&mut tty));
+
+        match validate_token_code(&code) {
+            Ok(()) => return Ok(code),
+            Err(err) => try!(writeln!(tty, "{}", err)),
+        }
+    }
+
+    Err(StsCliError::Error("no valid MFA token code was entered".to_owned()))
+}
+
+#[cfg(not(unix))]
+fn prompt_token_code(serial_number: &str) -> Result<String> {
+    use std::io;
+
+    let mut stderr = io::stderr();
+
+    for _ in 0..PROMPT_ATTEMPTS {
+        try!(write!(stderr, "MFA token code for {}: ", serial_number));
+        try!(stderr.flush());
+
+        let mut line = String::new();
+        try!(io::stdin().read_line(&mut line));
+        let code = line.trim().to_owned();
+
+        match validate_token_code(&code) {
+            Ok(()) => return Ok(code),
+            Err(err) => try!(writeln!(stderr, "{}", err)),
+        }
+    }
+
+    Err(StsCliError::Error("no valid MFA token code was entered".to_owned()))
+}
+
+#[cfg(unix)]
+mod tty {
+    use libc;
+    use std::fs::File;
+    use std::io;
+    use std::io::{Read, Write};
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use result::*;
+
+    /// Turns off echo, line buffering and signals until dropped.
+    struct RawMode {
+        fd: RawFd,
+        original: libc::termios,
+    }
+
+    impl RawMode {
+        fn enable(fd: RawFd) -> Result<RawMode> {
+            let mut termios: libc::termios = unsafe { mem::zeroed() };
+
+            if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+                return Err(StsCliError::from(io::Error::last_os_error()));
+            }
+
+            let original = termios;
+
+            termios.c_lflag &= !(libc::ECHO | libc::ICANON | libc::ISIG);
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+
+            if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+                return Err(StsCliError::from(io::Error::last_os_error()));
+            }
+
+            Ok(RawMode {
+                fd: fd,
+                original: original,
+            })
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.original) };
+        }
+    }
+
+    /// Reads a line from the terminal, echoing `*` for each character typed.
+    #[allow(clippy::manual_range_contains)] // Range::contains is newer than the Rust this crate targets
+    pub fn read_masked(tty: &mut File) -> Result<String> {
+        let raw_mode = try!(RawMode::enable(tty.as_raw_fd()));
+
+        let mut line = String::new();
+        let mut byte = [0u8; 1];
+
+        loop {
+            if try!(tty.read(&mut byte)) == 0 {
+                break;
+            }
+
+            match byte[0] {
+                b'\n' | b'\r' => break,
+                // ctrl-c and ctrl-d, which don't raise signals while the terminal is raw
+                0x03 | 0x04 => {
+                    drop(raw_mode);
+                    try!(tty.write_all(b"\n"));
+                    return Err(StsCliError::Error("cancelled".to_owned()));
+                },
+                // backspace and delete
+                0x08 | 0x7f if !line.is_empty() => {
+                    line.pop();
+                    try!(tty.write_all(b"\x08 \x08"));
+                },
+                c if c >= 0x20 && c < 0x7f => {
+                    line.push(c as char);
+                    try!(tty.write_all(b"*"));
+                },
+                _ => {}
+            }
+        }
+
+        drop(raw_mode);
+        try!(tty.write_all(b"\n"));
+
+        Ok(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    const SERIAL_NUMBER: &'static str = "arn:aws:iam::111111111111:mfa/user";
+
+    #[test]
+    fn token_codes_are_six_digits() {
+        assert!(validate_token_code("123456").is_ok());
+        assert!(validate_token_code("012345").is_ok());
+
+        assert!(validate_token_code("12345").is_err());
+        assert!(validate_token_code("1234567").is_err());
+        assert!(validate_token_code("12345a").is_err());
+        assert!(validate_token_code("12-456").is_err());
+        assert!(validate_token_code("").is_err());
+        // only ASCII digits, even where another script's are six characters long
+        assert!(validate_token_code("\u{661}\u{662}\u{663}\u{664}\u{665}\u{666}").is_err());
+    }
+
+    #[test]
+    fn token_codes_are_checked_as_they_are() {
+        // whitespace is only trimmed from codes that are read from a command or the terminal
+        assert!(validate_token_code(" 123456").is_err());
+        assert!(validate_token_code("123456\n").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn token_code_commands_get_the_serial_number() {
+        let command = format!("test \"$STSCLI_MFA_SERIAL\" = {} && echo ' 654321 '", SERIAL_NUMBER);
+
+        assert_eq!(run_token_code_command(&command, SERIAL_NUMBER).unwrap(), "654321");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn token_code_command_errors() {
+        let failed = run_token_code_command("exit 1", SERIAL_NUMBER).unwrap_err().to_string();
+        assert!(failed.contains("failed"), "{}", failed);
+
+        let empty = run_token_code_command("echo", SERIAL_NUMBER).unwrap_err().to_string();
+        assert!(empty.contains("printed nothing"), "{}", empty);
+
+        let invalid = run_token_code_command("echo 12345", SERIAL_NUMBER).unwrap_err().to_string();
+        assert!(invalid.contains("must be 6 digits"), "{}", invalid);
+    }
+}
@@ -0,0 +1,187 @@
+use chrono::Utc;
+use std::cmp;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use files;
+use result::*;
+
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+
+/// The hidden subcommand that deletes an output file once it is due.
+pub const DELETE_SUBCOMMAND: &'static str = "delete-output-file";
+
+/// How long to sleep at a time while waiting, so time spent suspended is noticed.
+const POLL_SECONDS: i64 = 60;
+
+/// Writes the output atomically to a file only the current user can read.
+///
+/// With `delete_after`, a background stscli process removes the file that many seconds later,
+/// unless it has been replaced in the meantime.
+pub fn write(path: &Path, contents: &str, delete_after: Option<i64>) -> Result<()> {
+    try!(files::write_private_file_atomic(path, contents.as_bytes())
+        .map_err(|e| e.with_context(format!("can't write {}", path.display()))));
+
+    if let Some(seconds) = delete_after {
+        try!(delete_later(path, seconds));
+    }
+
+    Ok(())
+}
+
+/// Starts a detached stscli process, running the hidden `delete-output-file` subcommand, which sleeps until
+/// the file is due. It holds nothing open, but stays in the process list until then, and the file is left
+/// if it is killed or the machine restarts first.
+fn delete_later(path: &Path, seconds: i64) -> Result<()> {
+    let path = try!(fs::canonicalize(path));
+    let delete_at = Utc::now().timestamp() + seconds;
+
+    let mut command = process::Command::new(try!(env::current_exe()));
+    command.arg(DELETE_SUBCOMMAND)
+        .arg("--at").arg(delete_at.to_string())
+        .arg(&path)
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null());
+
+    if let Some(id) = try!(file_id(&path)) {
+        command.arg("--file-id").arg(id.to_string());
+    }
+
+    try!(command.spawn());
+
+    Ok(())
+}
+
+/// Waits until `delete_at`, a Unix timestamp, then removes the file if it is still the one that was written.
+pub fn delete_when_due(path: &Path, delete_at: i64, expected_id: Option<u64>) -> Result<()> {
+    ignore_hangup();
+
+    loop {
+        let remaining = delete_at - Utc::now().timestamp();
+
+        if remaining <= 0 {
+            break;
+        }
+
+        thread::sleep(Duration::from_secs(cmp::min(remaining, POLL_SECONDS) as u64));
+    }
+
+    match (try!(file_id(path)), expected_id) {
+        (None, _) => Ok(()),
+        (Some(id), Some(expected_id)) if id == expected_id => match fs::remove_file(path) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result.map_err(From::from),
+        },
+        _ => {
+            info!("{} has been replaced, or can't be told apart from a newer file, leaving it", path.display());
+            Ok(())
+        },
+    }
+}
+
+/// Identifies the file, so a newer one written to the same path isn't deleted, or None if it doesn't exist.
+/// Atomic writes replace the file, so it gets a new inode each time.
+#[cfg(unix)]
+fn file_id(path: &Path) -> Result<Option<u64>> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.ino())),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(StsCliError::from(err)),
+    }
+}
+
+/// Without inodes, the time the file was written, in nanoseconds, identifies it instead.
+#[cfg(not(unix))]
+fn file_id(path: &Path) -> Result<Option<u64>> {
+    use std::time::UNIX_EPOCH;
+
+    match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => {
+            let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+            Ok(Some(since_epoch.as_secs() * 1000000000 + since_epoch.subsec_nanos() as u64))
+        },
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(StsCliError::from(err)),
+    }
+}
+
+/// Keeps waiting after the terminal stscli was started from is closed.
+#[cfg(unix)]
+fn ignore_hangup() {
+    use libc;
+
+    unsafe { libc::signal(libc::SIGHUP, libc::SIG_IGN) };
+}
+
+#[cfg(not(unix))]
+fn ignore_hangup() {}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::process;
+    use super::*;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("stscli-output-file-test-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+
+        dir.join("session.env")
+    }
+
+    fn contents(path: &Path) -> String {
+        let mut contents = String::new();
+        fs::File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn writes_the_file() {
+        let path = temp_file("write");
+
+        write(&path, "KEY=value\n", None).unwrap();
+        assert_eq!(contents(&path), "KEY=value\n");
+
+        write(&path, "KEY=other\n", None).unwrap();
+        assert_eq!(contents(&path), "KEY=other\n");
+    }
+
+    #[test]
+    fn deletes_the_file_that_was_written() {
+        let path = temp_file("delete");
+        write(&path, "KEY=value\n", None).unwrap();
+        let id = file_id(&path).unwrap();
+        assert!(id.is_some());
+
+        delete_when_due(&path, Utc::now().timestamp() - 1, id).unwrap();
+        assert!(!path.exists());
+        assert_eq!(file_id(&path).unwrap(), None);
+
+        // already gone
+        delete_when_due(&path, Utc::now().timestamp() - 1, id).unwrap();
+    }
+
+    #[test]
+    fn leaves_a_replaced_file() {
+        let path = temp_file("replaced");
+        write(&path, "KEY=value\n", None).unwrap();
+        let id = file_id(&path).unwrap();
+
+        write(&path, "KEY=newer\n", None).unwrap();
+        delete_when_due(&path, Utc::now().timestamp() - 1, id).unwrap();
+        assert_eq!(contents(&path), "KEY=newer\n");
+
+        delete_when_due(&path, Utc::now().timestamp() - 1, None).unwrap();
+        assert!(path.exists());
+    }
+}
@@ -0,0 +1,210 @@
+use rusoto_core::param::{Params, ServiceParams};
+use serde_json;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use result::*;
+use tags::SessionTags;
+
+/// STS limits the plain text of inline and managed session policies together to this many characters.
+pub const MAX_POLICY_PLAINTEXT: usize = 2048;
+pub const MAX_POLICY_ARNS: usize = 10;
+/// STS packs the session policies and tags together and compresses them, with a separate limit on the result.
+/// The packing isn't documented, so this is an estimate, see `SessionPolicy::check_packed_size`.
+pub const MAX_PACKED_SIZE_ESTIMATE: usize = 2048;
+
+/// Policies that scope down the permissions of a session.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionPolicy {
+    /// An inline IAM policy, minified.
+    pub policy: Option<String>,
+    /// The ARNs of managed policies.
+    pub policy_arns: Vec<String>,
+}
+
+impl SessionPolicy {
+    /// Reads an inline policy from a file, or stdin if the file name is `-`, and checks it and the ARNs against the STS limits.
+    pub fn load(policy_file: Option<&Path>, policy_arns: Vec<String>) -> Result<SessionPolicy> {
+        let policy = if let Some(path) = policy_file {
+            let mut contents = String::new();
+
+            if path == Path::new("-") {
+                try!(io::stdin().read_to_string(&mut contents));
+            } else {
+                let mut file = try!(File::open(path)
+                    .map_err(|e| StsCliError::Error(format!("can't open policy file {}: {}", path.display(), e))));
+                try!(file.read_to_string(&mut contents));
+            }
+
+            let document: serde_json::Value = try!(serde_json::from_str(&contents)
+                .map_err(|e| StsCliError::Error(format!("policy file {} is not valid JSON: {}", path.display(), e))));
+
+            if !document.is_object() {
+                return Err(StsCliError::Error(format!("policy file {} must contain a JSON object", path.display())));
+            }
+
+            Some(try!(serde_json::to_string(&document)))
+        } else {
+            None
+        };
+
+        let session_policy = SessionPolicy {
+            policy: policy,
+            policy_arns: policy_arns,
+        };
+
+        try!(session_policy.validate());
+
+        Ok(session_policy)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.policy.is_none() && self.policy_arns.is_empty()
+    }
+
+    fn validate(&self) -> Result<()> {
+        for arn in &self.policy_arns {
+            if !arn.starts_with("arn:") || !arn.contains(":policy/") {
+                return Err(StsCliError::Error(format!("{} is not the ARN of a managed policy, e.g. arn:aws:iam::aws:policy/ReadOnlyAccess", arn)));
+            }
+        }
+
+        if self.policy_arns.len() > MAX_POLICY_ARNS {
+            return Err(StsCliError::Error(format!("too many policy ARNs: STS allows at most {} managed session policies", MAX_POLICY_ARNS)));
+        }
+
+        let plaintext = self.plaintext_len();
+
+        if plaintext > MAX_POLICY_PLAINTEXT {
+            return Err(StsCliError::Error(format!("session policies are too large: {} characters after removing whitespace, but STS allows at most {}",
+                plaintext, MAX_POLICY_PLAINTEXT)));
+        }
+
+        Ok(())
+    }
+
+    fn plaintext_len(&self) -> usize {
+        self.policy.as_ref().map(|p| p.len()).unwrap_or(0) + self.policy_arns.iter().map(|arn| arn.len()).sum::<usize>()
+    }
+
+    /// Estimates the packed size of these policies together with the session's tags, which count towards the same limit.
+    ///
+    /// Compression is assumed to at best halve the plain text, so only sessions that STS would surely refuse
+    /// are refused here. Policies full of repetition may still be refused by STS.
+    pub fn estimate_packed_size(&self, session_tags: &SessionTags) -> usize {
+        let tags = session_tags.tags.iter().map(|tag| tag.0.len() + tag.1.len()).sum::<usize>()
+            + session_tags.transitive_tag_keys.iter().map(|key| key.len()).sum::<usize>();
+
+        (self.plaintext_len() + tags) / 2
+    }
+
+    pub fn check_packed_size(&self, session_tags: &SessionTags) -> Result<()> {
+        let estimate = self.estimate_packed_size(session_tags);
+
+        if estimate > MAX_PACKED_SIZE_ESTIMATE {
+            return Err(StsCliError::Error(format!(
+                "session policies and tags are too large: they would pack to about {} bytes, but STS allows at most {}. Use fewer or shorter tags",
+                estimate, MAX_PACKED_SIZE_ESTIMATE)));
+        }
+
+        Ok(())
+    }
+
+    /// The `PolicyArns` request parameters, which the STS client doesn't support.
+    pub fn params(&self) -> Params {
+        let mut params = Params::new();
+
+        for (i, arn) in self.policy_arns.iter().enumerate() {
+            params.put(&format!("PolicyArns.member.{}.arn", i + 1), arn);
+        }
+
+        params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use files;
+    use super::*;
+
+    fn policy_file(name: &str, contents: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("stscli-policy-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        files::write_private_file_atomic(&path, contents.as_bytes()).unwrap();
+        path
+    }
+
+    fn arns(arns: &[&str]) -> Vec<String> {
+        arns.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn minifies_the_policy() {
+        let path = policy_file("minify.json", "{\n  \"Version\": \"2012-10-17\",\n  \"Statement\": []\n}\n");
+
+        let policy = SessionPolicy::load(Some(&path), vec![]).unwrap();
+
+        assert_eq!(policy.policy, Some("{\"Statement\":[],\"Version\":\"2012-10-17\"}".to_owned()));
+        assert!(!policy.is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_policy_files() {
+        assert!(SessionPolicy::load(Some(&policy_file("invalid.json", "{\"Version\":")), vec![]).is_err());
+        assert!(SessionPolicy::load(Some(&policy_file("array.json", "[]")), vec![]).is_err());
+        assert!(SessionPolicy::load(Some(Path::new("/nonexistent/policy.json")), vec![]).is_err());
+
+        let statement = format!("{{\"Statement\":[{{\"Effect\":\"Allow\",\"Action\":\"s3:GetObject\",\"Resource\":\"{}\"}}]}}", "a".repeat(MAX_POLICY_PLAINTEXT));
+        assert!(SessionPolicy::load(Some(&policy_file("large.json", &statement)), vec![]).is_err());
+    }
+
+    #[test]
+    fn estimates_the_packed_size_with_the_tags() {
+        let arn = "arn:aws:iam::aws:policy/ReadOnlyAccess";
+        let policy = SessionPolicy {
+            policy: Some("x".repeat(1000)),
+            policy_arns: arns(&[arn]),
+        };
+        let session_tags = SessionTags {
+            tags: vec![("Project".to_owned(), "stscli".to_owned())],
+            transitive_tag_keys: vec!["Project".to_owned()],
+            source_identity: None,
+        };
+
+        assert_eq!(policy.estimate_packed_size(&session_tags), (1000 + arn.len() + 7 + 6 + 7) / 2);
+        assert!(policy.check_packed_size(&session_tags).is_ok());
+
+        // each tag is within its own limits, but together with the policy they are too large
+        let long_tags = SessionTags::new((0..20).map(|i| (format!("Key{}", i), "v".repeat(256))).collect(), vec![], None).unwrap();
+        assert!(policy.check_packed_size(&long_tags).is_err());
+        assert!(SessionPolicy::default().check_packed_size(&long_tags).is_err());
+        assert!(policy.check_packed_size(&SessionTags::default()).is_ok());
+    }
+
+    #[test]
+    fn checks_policy_arns() {
+        assert!(SessionPolicy::load(None, arns(&["arn:aws:iam::aws:policy/ReadOnlyAccess"])).is_ok());
+        assert!(SessionPolicy::load(None, arns(&["ReadOnlyAccess"])).is_err());
+        assert!(SessionPolicy::load(None, arns(&["arn:aws:iam::111111111111:role/admin"])).is_err());
+
+        let too_many: Vec<String> = (0..MAX_POLICY_ARNS + 1).map(|i| format!("arn:aws:iam::aws:policy/P{}", i)).collect();
+        assert!(SessionPolicy::load(None, too_many).is_err());
+    }
+
+    #[test]
+    fn params_are_numbered_from_one() {
+        let policy = SessionPolicy::load(None, arns(&["arn:aws:iam::aws:policy/A", "arn:aws:iam::aws:policy/B"])).unwrap();
+        let params = policy.params();
+
+        assert_eq!(params.get("PolicyArns.member.1.arn"), Some(&Some("arn:aws:iam::aws:policy/A".to_owned())));
+        assert_eq!(params.get("PolicyArns.member.2.arn"), Some(&Some("arn:aws:iam::aws:policy/B".to_owned())));
+        assert!(SessionPolicy::load(None, vec![]).unwrap().is_empty());
+    }
+}
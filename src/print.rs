@@ -1,116 +1,1024 @@
-use regex::Regex;
+use std::env;
+use std::io;
 use std::process;
-use clap::ArgMatches;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use serde_json;
 use std::ffi::OsStr;
+use rusoto_core::AwsCredentials;
 use result::*;
-use config::*;
+use signals;
+
+/// Variables that say where to get credentials, or describe a session, which would conflict with ours.
+const CONFLICTING_VARS: [&'static str; 17] = [
+    "AWS_PROFILE", "AWS_DEFAULT_PROFILE",
+    "AWS_ACCESS_KEY_ID", "AWS_SECRET_ACCESS_KEY", "AWS_SESSION_TOKEN", "AWS_SECURITY_TOKEN",
+    "AWS_CREDENTIAL_EXPIRATION", "AWS_SESSION_EXPIRATION",
+    "AWS_ROLE_ARN", "AWS_ROLE_SESSION_NAME", "AWS_WEB_IDENTITY_TOKEN_FILE",
+    "AWS_CONTAINER_CREDENTIALS_FULL_URI", "AWS_CONTAINER_CREDENTIALS_RELATIVE_URI", "AWS_CONTAINER_AUTHORIZATION_TOKEN",
+    "STSCLI_PROFILE", "STSCLI_ROLE_ARN", "STSCLI_ASSUMED_ROLE_ARN",
+];
+
+/// Variables an isolated command still gets, since most programs need them to run.
+const ISOLATED_VARS: [&'static str; 18] = [
+    "PATH", "HOME", "USER", "LOGNAME", "SHELL", "TERM", "LANG", "LC_ALL", "TZ", "TMPDIR",
+    "SYSTEMROOT", "COMSPEC", "PATHEXT", "TEMP", "TMP", "USERPROFILE", "APPDATA", "LOCALAPPDATA",
+];
+
+/// How much of the environment a command inherits from stscli.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Inherit {
+    /// Everything except variables that conflict with the session's.
+    Scrubbed,
+    /// Only the variables needed to run programs, such as `PATH` and `HOME`.
+    Isolated,
+}
+
+/// The names `--format` accepts.
+pub const OUTPUT_FORMATS: [&'static str; 14] = [
+    "bash", "zsh", "fish", "csh", "tcsh", "nushell", "elvish", "powershell", "cmd",
+    "dotenv", "docker-env", "systemd", "k8s-secret", "json",
+];
 
 #[derive(Copy, Clone, Debug)]
 pub enum OutputFormat {
     Bash { export: bool },
+    Zsh { export: bool },
     Fish { export: bool },
+    Csh { export: bool },
+    Nushell { export: bool },
+    Elvish { export: bool },
     Powershell { export: bool },
+    /// A batch file for `cmd.exe`, where every variable is exported.
+    Cmd,
+    /// A `.env` file, as read by docker-compose and the dotenv libraries.
+    Dotenv,
+    /// A file for `docker run --env-file`, which takes everything after the `=` as it is.
+    DockerEnv,
+    /// A file for systemd's `EnvironmentFile=`.
+    Systemd,
+    /// A Kubernetes Secret manifest, see `k8s::secret_manifest`.
+    K8sSecret,
     Json
 }
 
-pub fn print_vars_json(_args: &ArgMatches, _config: &Config, vars: &HashMap<String, String>) -> Result<()> {
-    let vars_json = try!(serde_json::to_string(&vars).map_err(StsCliError::from));
+impl OutputFormat {
+    pub fn from_name(name: &str, export: bool) -> Result<OutputFormat> {
+        match name {
+            "bash" => Ok(OutputFormat::Bash { export: export }),
+            "zsh" => Ok(OutputFormat::Zsh { export: export }),
+            "fish" => Ok(OutputFormat::Fish { export: export }),
+            "csh" | "tcsh" => Ok(OutputFormat::Csh { export: export }),
+            "nushell" => Ok(OutputFormat::Nushell { export: export }),
+            "elvish" => Ok(OutputFormat::Elvish { export: export }),
+            "powershell" => Ok(OutputFormat::Powershell { export: export }),
+            "cmd" => Ok(OutputFormat::Cmd),
+            "dotenv" => Ok(OutputFormat::Dotenv),
+            "docker-env" => Ok(OutputFormat::DockerEnv),
+            "systemd" => Ok(OutputFormat::Systemd),
+            "k8s-secret" => Ok(OutputFormat::K8sSecret),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(StsCliError::Error(format!("unknown format {}. use one of {}", name, OUTPUT_FORMATS.join(", ")))),
+        }
+    }
+
+    /// Whether the format has no way to put a line break in a value.
+    #[allow(clippy::match_like_matches_macro)] // no matches! in the Rust this crate targets
+    fn is_single_line(&self) -> bool {
+        match *self {
+            OutputFormat::Cmd | OutputFormat::DockerEnv | OutputFormat::Systemd => true,
+            _ => false,
+        }
+    }
+}
+
+/// The text that sets the variables, one line each in order of name, or a JSON object.
+///
+/// A Secret manifest needs a name, so `k8s::secret_manifest` writes those instead.
+pub fn format_vars(vars: &HashMap<String, String>, output_format: OutputFormat) -> Result<String> {
+    let vars: BTreeMap<&String, &String> = vars.iter().collect();
+
+    match output_format {
+        OutputFormat::Json => return Ok(format!("{}\n", try!(serde_json::to_string(&vars)))),
+        OutputFormat::K8sSecret => return Err(k8s_secret_needs_a_name()),
+        _ => {},
+    }
+
+    let mut text = String::new();
+
+    for (k, v) in vars {
+        text.push_str(&try!(format_var(k, v, output_format)));
+        text.push('\n');
+    }
+
+    Ok(text)
+}
+
+/// What the AWS CLI and SDKs expect a `credential_process` to print.
+#[derive(Debug, Serialize)]
+struct CredentialProcessOutput<'a> {
+    #[serde(rename = "Version")]
+    version: u32,
+    #[serde(rename = "AccessKeyId")]
+    access_key_id: &'a str,
+    #[serde(rename = "SecretAccessKey")]
+    secret_access_key: &'a str,
+    #[serde(rename = "SessionToken", skip_serializing_if = "Option::is_none")]
+    session_token: Option<&'a str>,
+    #[serde(rename = "Expiration")]
+    expiration: String,
+}
 
-    println!("{}", vars_json);
+pub fn print_credential_process(creds: &AwsCredentials) -> Result<()> {
+    println!("{}", try!(format_credential_process(creds)));
 
     Ok(())
 }
 
-pub fn print_vars(_args: &ArgMatches, _config: &Config, vars: &HashMap<String, String>, output_format: OutputFormat) -> Result<()> {
-    for (k, v) in vars {
-        print_var(k, v, output_format);
+fn format_credential_process(creds: &AwsCredentials) -> Result<String> {
+    let output = CredentialProcessOutput {
+        version: 1,
+        access_key_id: creds.aws_access_key_id(),
+        secret_access_key: creds.aws_secret_access_key(),
+        session_token: creds.token().as_ref().map(|s| s.as_str()),
+        expiration: creds.expires_at().to_rfc3339(),
+    };
+
+    Ok(try!(serde_json::to_string(&output)))
+}
+
+/// Who a profile's credentials belong to, or why they couldn't be checked.
+#[derive(Debug, Default, Serialize)]
+pub struct Identity {
+    #[serde(rename = "Profile", skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(rename = "Account", skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    #[serde(rename = "Arn", skip_serializing_if = "Option::is_none")]
+    pub arn: Option<String>,
+    #[serde(rename = "UserId", skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    #[serde(rename = "Error", skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(rename = "Skipped", skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<String>,
+}
+
+pub fn print_identity(identity: &Identity, json: bool) -> Result<()> {
+    if json {
+        println!("{}", try!(serde_json::to_string(identity)));
+    } else {
+        println!("Account\t{}", identity.account.as_ref().map(|s| &s[..]).unwrap_or(""));
+        println!("Arn\t{}", identity.arn.as_ref().map(|s| &s[..]).unwrap_or(""));
+        println!("UserId\t{}", identity.user_id.as_ref().map(|s| &s[..]).unwrap_or(""));
     }
 
     Ok(())
 }
 
-pub fn print_var(k: &str, v: &str, output_format: OutputFormat) {
-    match output_format {
-        OutputFormat::Bash{export} => {
-            print_bash_var(k, v, export);
-        },
-        OutputFormat::Fish{export} => {
-            print_var_fish(k, v, export);
-        },
-        OutputFormat::Powershell{export} => {
-            print_var_ps(k, v, export);
-        },
-        _ => unreachable!()
+/// Prints one line per profile, or a JSON array.
+pub fn print_identities(identities: &[Identity], json: bool) -> Result<()> {
+    if json {
+        println!("{}", try!(serde_json::to_string(identities)));
+        return Ok(());
+    }
+
+    for identity in identities {
+        let profile = identity.profile.as_ref().map(|s| &s[..]).unwrap_or("");
+
+        if let Some(ref error) = identity.error {
+            println!("{}\tFAILED\t{}", profile, error);
+        } else if let Some(ref reason) = identity.skipped {
+            println!("{}\tSKIPPED\t{}", profile, reason);
+        } else {
+            println!("{}\tOK\t{}", profile, identity.arn.as_ref().map(|s| &s[..]).unwrap_or(""));
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the command, on Unix by replacing this process unless `fork` is set.
+///
+/// When forked, signals are passed on to the command and its exit status is
+/// returned as `ChildExited` or `ChildKilled`, so the caller can exit the same way.
+pub fn spawn_command<S>(command_str: &OsStr, args: &[S], env: &HashMap<String, String>, inherit: Inherit, fork: bool) -> Result<()> where S: AsRef<OsStr> {
+    let mut command = build_command(command_str, args, env, inherit);
+
+    if !fork {
+        return replace_process(command_str, command);
+    }
+
+    let status = {
+        let forwarding = signals::forward();
+        let mut child = try!(command.spawn().map_err(|err| cant_run(command_str, err)));
+        forwarding.set_child(child.id());
+        try!(child.wait())
     };
+
+    exit_status_result(status)
 }
 
-pub fn spawn_command<S>(command_str: &OsStr, args: &[S], env: &HashMap<String, String>) -> Result<()> where S: AsRef<OsStr> {
-        
+/// The command with the variables in `env` on top of what it inherits from stscli.
+fn build_command<S>(command_str: &OsStr, args: &[S], env: &HashMap<String, String>, inherit: Inherit) -> process::Command where S: AsRef<OsStr> {
     let mut command = process::Command::new(command_str);
     command.args(args);
 
+    match inherit {
+        Inherit::Scrubbed => {
+            for key in CONFLICTING_VARS.iter() {
+                command.env_remove(key);
+            }
+
+            // AWS_REGION takes precedence over AWS_DEFAULT_REGION in most SDKs
+            if env.contains_key("AWS_DEFAULT_REGION") {
+                command.env_remove("AWS_REGION");
+            }
+        },
+        Inherit::Isolated => {
+            command.env_clear();
+
+            for key in ISOLATED_VARS.iter() {
+                if let Some(value) = env::var_os(key) {
+                    command.env(key, value);
+                }
+            }
+        },
+    }
+
     for (k,v) in env {
         command.env(k, v);
     }
 
-    {
-        let mut result = try!(command.spawn());
-        
-        let status = try!(result.wait());
-        
-        status.code().ok_or(StsCliError::ProcessKilled).and_then(|code| {
-            if code == 0 {
-                Ok(())
+    command
+}
+
+#[cfg(unix)]
+fn replace_process(command_str: &OsStr, mut command: process::Command) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    // exec only returns if the command couldn't be run
+    Err(cant_run(command_str, command.exec()))
+}
+
+#[cfg(not(unix))]
+fn replace_process(command_str: &OsStr, mut command: process::Command) -> Result<()> {
+    let status = try!(command.status().map_err(|err| cant_run(command_str, err)));
+
+    exit_status_result(status)
+}
+
+fn cant_run(command_str: &OsStr, err: io::Error) -> StsCliError {
+    StsCliError::Error(format!("can't run {}: {}", command_str.to_string_lossy(), err))
+}
+
+#[cfg(unix)]
+fn exit_status_result(status: process::ExitStatus) -> Result<()> {
+    use std::os::unix::process::ExitStatusExt;
+
+    match (status.code(), status.signal()) {
+        (Some(0), _) => Ok(()),
+        (Some(code), _) => Err(StsCliError::ChildExited(code)),
+        (None, Some(signal)) => Err(StsCliError::ChildKilled(signal)),
+        (None, None) => Err(StsCliError::ProcessKilled),
+    }
+}
+
+#[cfg(not(unix))]
+fn exit_status_result(status: process::ExitStatus) -> Result<()> {
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(code) => Err(StsCliError::ChildExited(code)),
+        None => Err(StsCliError::ProcessKilled),
+    }
+}
+
+/// The line that sets the variable in the shell, with the value quoted so the shell reads it back unchanged.
+pub fn format_var(k: &str, v: &str, output_format: OutputFormat) -> Result<String> {
+    if output_format.is_single_line() && (v.contains('\n') || v.contains('\r')) {
+        return Err(StsCliError::Error(format!("the value of {} has a line break, which the format can't hold", k)));
+    }
+
+    let line = match output_format {
+        OutputFormat::Bash{export} | OutputFormat::Zsh{export} => {
+            let export_prefix = if export { "export " } else { "" };
+            format!("{}{}={}", export_prefix, k, quote_posix(v))
+        },
+        OutputFormat::Fish{export} => {
+            let export_prefix = if export { "set -x" } else { "set" };
+            format!("{} {} {}", export_prefix, k, quote_fish(v))
+        },
+        OutputFormat::Csh{export} => {
+            // the semicolon lets eval `stscli get --format csh` run the lines that backquotes join together
+            if export {
+                format!("setenv {} {};", k, quote_csh(v))
             } else {
-                Err(StsCliError::ChildExited(code))
+                format!("set {} = {};", k, quote_csh(v))
             }
-        })
-    }
+        },
+        OutputFormat::Nushell{export} => {
+            if export {
+                format!("$env.{} = {}", k, quote_nushell(v))
+            } else {
+                format!("let {} = {}", k, quote_nushell(v))
+            }
+        },
+        OutputFormat::Elvish{export} => {
+            if export {
+                format!("set-env {} {}", k, quote_elvish(v))
+            } else {
+                format!("var {} = {}", k, quote_elvish(v))
+            }
+        },
+        OutputFormat::Powershell{export} => {
+            let export_prefix = if export { "env:" } else { "" };
+            format!("${}{} = {}", export_prefix, k, quote_powershell(v))
+        },
+        OutputFormat::Cmd => {
+            // @ stops the batch file echoing the line, and with it the value
+            format!("@set {}={}", k, escape_cmd(v))
+        },
+        OutputFormat::Dotenv => format!("{}={}", k, quote_dotenv(v)),
+        OutputFormat::DockerEnv => format!("{}={}", k, v),
+        OutputFormat::Systemd => format!("{}={}", k, quote_systemd(v)),
+        OutputFormat::Json => return Err(StsCliError::Error(format!("{} can't be written as a line of json, only as part of an object", k))),
+        OutputFormat::K8sSecret => return Err(k8s_secret_needs_a_name()),
+    };
+
+    Ok(line)
 }
 
-fn print_bash_var(k: &str, v: &str, export_vars: bool) {
-    let re = shell_re();
+fn k8s_secret_needs_a_name() -> StsCliError {
+    StsCliError::Error("a k8s-secret needs a name, so it is written by k8s::secret_manifest".to_owned())
+}
 
-    let export_prefix = if export_vars { "export " } else { "" };
+/// Quotes a string for sh, bash and zsh. Nothing is special inside single quotes, so a
+/// single quote is written by closing the quotes, adding an escaped one and opening them again.
+pub fn quote_posix(s: &str) -> String {
+    format!("'{}'", s.replace("'", "'\\''"))
+}
 
-    let escaped = re.replace_all(v, shell_esc());
-    println!("{}{}=\"{}\"", export_prefix, k, escaped);
+/// Quotes a string for fish, where only `\\` and `\'` are escapes inside single quotes.
+pub fn quote_fish(s: &str) -> String {
+    format!("'{}'", s.replace("\\", "\\\\").replace("'", "\\'"))
 }
 
-fn print_var_fish(k: &str, v: &str, export_vars: bool) {
-    let re = shell_re();
+/// Quotes a string for PowerShell, where a quote inside single quotes is written twice.
+/// PowerShell also takes the typographic single quotes as quotes, so those are doubled too.
+pub fn quote_powershell(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+
+    quoted.push('\'');
+    for c in s.chars() {
+        if is_powershell_quote(c) {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
 
-    let export_prefix = if export_vars { "set -x" } else { "set" };
+    quoted
+}
 
-    let escaped = re.replace_all(v, shell_esc());
-    println!("{} {} \"{}\"", export_prefix, k, escaped);
+/// Quotes a string for csh and tcsh. Inside single quotes, history substitution with `!` still
+/// happens and a line break has to be escaped, so both get a backslash.
+pub fn quote_csh(s: &str) -> String {
+    format!("'{}'", s.replace("'", "'\\''").replace("!", "\\!").replace("\n", "\\\n"))
 }
 
-fn print_var_ps(k: &str, v: &str, export_vars: bool) {
-    let re = powershell_re();
+/// Quotes a string for nushell. Double quoted strings aren't interpolated and understand
+/// backslash escapes, so control characters can be written out.
+pub fn quote_nushell(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
 
-    let export_prefix = if export_vars { "env:" } else { "" };
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
 
-    let escaped = re.replace_all(v, powershell_esc());
-    println!("${}{} = \"{}\"", export_prefix, k, escaped);
+    quoted
 }
 
-fn shell_re() -> Regex {
-    let pattern = r#"[\\"]"#;
-    Regex::new(pattern).unwrap()
+/// Quotes a string for elvish, where a quote inside single quotes is written twice.
+pub fn quote_elvish(s: &str) -> String {
+    format!("'{}'", s.replace("'", "''"))
 }
 
-fn shell_esc() -> &'static str {
-    "\\$0"
+/// Escapes a string for `set` in a batch file. The characters cmd treats specially are escaped
+/// with `^`, and `%` is doubled.
+pub fn escape_cmd(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '^' | '&' | '|' | '<' | '>' | '(' | ')' | '"' => { escaped.push('^'); escaped.push(c); },
+            '%' => escaped.push_str("%%"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
 }
 
-fn powershell_re() -> Regex {
-    let pattern = r#"[\0\r\n\t`"]"#;
-    Regex::new(pattern).unwrap()
+/// Quotes a string for a `.env` file. Single quoted values are taken as they are, so they are
+/// used unless the value has a single quote or line break, which need double quotes and escapes.
+pub fn quote_dotenv(s: &str) -> String {
+    if !s.contains(&['\'', '\n', '\r'][..]) {
+        return format!("'{}'", s);
+    }
+
+    let mut quoted = String::with_capacity(s.len() + 2);
+
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' | '$' => { quoted.push('\\'); quoted.push(c); },
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
 }
 
-fn powershell_esc() -> &'static str {
-    "`$0"
+/// Quotes a string for a systemd environment file, where `"`, `\`, `` ` `` and `$` are escaped inside double quotes.
+pub fn quote_systemd(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' | '`' | '$' => { quoted.push('\\'); quoted.push(c); },
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+#[allow(clippy::match_like_matches_macro)] // no matches! in the Rust this crate targets
+fn is_powershell_quote(c: char) -> bool {
+    match c {
+        '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}' => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{self, Rng, SeedableRng, StdRng};
+    use std::env;
+    use super::*;
+
+    type Unquote = fn(&str) -> String;
+
+    /// Characters that mean something to at least one of the shells.
+    const SPECIAL_CHARS: &'static [char] = &[
+        '\'', '"', '\\', '$', '`', '(', ')', '{', '}', '[', ']', ';', '&', '|', '<', '>', '*', '?', '~', '#', '%', '!',
+        ' ', '\t', '\n', '\r', '\u{2018}', '\u{2019}', '\u{201A}', '\u{201B}', '\u{201C}', '\u{201D}',
+    ];
+
+    /// A generator seeded from `STSCLI_TEST_SEED`, or randomly. The seed is printed, which the test
+    /// harness shows when a test fails, so the failure can be repeated.
+    fn seeded_rng() -> StdRng {
+        let seed = env::var("STSCLI_TEST_SEED").ok()
+            .map(|s| s.parse().expect("STSCLI_TEST_SEED must be a number"))
+            .unwrap_or_else(|| rand::thread_rng().gen::<usize>());
+
+        println!("random values from seed {}, set STSCLI_TEST_SEED={} to repeat them", seed, seed);
+
+        StdRng::from_seed(&[seed][..])
+    }
+
+    /// A string mixing special characters with any others, except NUL, which can't be in the environment.
+    fn random_value<R: Rng>(rng: &mut R) -> String {
+        let len = rng.gen_range(0, 40);
+
+        (0..len).map(|_| {
+            if rng.gen() {
+                *rng.choose(SPECIAL_CHARS).unwrap()
+            } else {
+                loop {
+                    let c = rng.gen::<char>();
+                    if c != '\0' {
+                        return c;
+                    }
+                }
+            }
+        }).collect()
+    }
+
+    /// Reads a word the way sh does, refusing anything outside quotes other than an escaped quote.
+    fn unquote_posix(word: &str) -> String {
+        let mut value = String::new();
+        let mut chars = word.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' => loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => value.push(c),
+                        None => panic!("unterminated quote in {:?}", word),
+                    }
+                },
+                '\\' => {
+                    assert_eq!(chars.next(), Some('\''), "unexpected escape in {:?}", word);
+                    value.push('\'');
+                },
+                c => panic!("unquoted {:?} in {:?}", c, word),
+            }
+        }
+
+        value
+    }
+
+    /// Reads a single quoted word the way fish does.
+    fn unquote_fish(word: &str) -> String {
+        let mut value = String::new();
+        let mut chars = word.chars();
+
+        assert_eq!(chars.next(), Some('\''), "unquoted {:?}", word);
+        loop {
+            match chars.next() {
+                Some('\'') => break,
+                Some('\\') => match chars.next() {
+                    Some(c @ '\\') | Some(c @ '\'') => value.push(c),
+                    Some(c) => { value.push('\\'); value.push(c); },
+                    None => panic!("unterminated quote in {:?}", word),
+                },
+                Some(c) => value.push(c),
+                None => panic!("unterminated quote in {:?}", word),
+            }
+        }
+        assert_eq!(chars.next(), None, "trailing characters in {:?}", word);
+
+        value
+    }
+
+    /// Reads a single quoted string the way PowerShell does.
+    fn unquote_powershell(word: &str) -> String {
+        let mut value = String::new();
+        let mut chars = word.chars().peekable();
+
+        assert!(chars.next().map(is_powershell_quote).unwrap_or(false), "unquoted {:?}", word);
+        loop {
+            match chars.next() {
+                Some(c) if is_powershell_quote(c) => {
+                    match chars.peek().cloned() {
+                        Some(next) if is_powershell_quote(next) => { value.push(next); chars.next(); },
+                        _ => break,
+                    }
+                },
+                Some(c) => value.push(c),
+                None => panic!("unterminated quote in {:?}", word),
+            }
+        }
+        assert_eq!(chars.next(), None, "trailing characters in {:?}", word);
+
+        value
+    }
+
+    /// Reads a single quoted word the way csh does, where `\!` and a backslashed line break are
+    /// escapes even inside the quotes, and refuses anything outside quotes other than an escaped quote.
+    fn unquote_csh(word: &str) -> String {
+        let mut value = String::new();
+        let mut chars = word.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\'' => loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some('!') => panic!("unescaped ! in {:?}", word),
+                        Some('\n') => panic!("unescaped line break in {:?}", word),
+                        Some('\\') => match chars.peek().cloned() {
+                            Some(next @ '!') | Some(next @ '\n') => { value.push(next); chars.next(); },
+                            _ => value.push('\\'),
+                        },
+                        Some(c) => value.push(c),
+                        None => panic!("unterminated quote in {:?}", word),
+                    }
+                },
+                '\\' => {
+                    assert_eq!(chars.next(), Some('\''), "unexpected escape in {:?}", word);
+                    value.push('\'');
+                },
+                c => panic!("unquoted {:?} in {:?}", c, word),
+            }
+        }
+
+        value
+    }
+
+    /// Reads a double quoted string the way nushell does.
+    fn unquote_nushell(word: &str) -> String {
+        let mut value = String::new();
+        let mut chars = word.chars();
+
+        assert_eq!(chars.next(), Some('"'), "unquoted {:?}", word);
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some('u') => {
+                        assert_eq!(chars.next(), Some('{'), "bad unicode escape in {:?}", word);
+                        let hex: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                        value.push(::std::char::from_u32(u32::from_str_radix(&hex, 16).unwrap()).unwrap());
+                    },
+                    c => panic!("unknown escape {:?} in {:?}", c, word),
+                },
+                Some(c) => value.push(c),
+                None => panic!("unterminated quote in {:?}", word),
+            }
+        }
+        assert_eq!(chars.next(), None, "trailing characters in {:?}", word);
+
+        value
+    }
+
+    /// Reads a single quoted string the way elvish does.
+    fn unquote_elvish(word: &str) -> String {
+        let mut value = String::new();
+        let mut chars = word.chars().peekable();
+
+        assert_eq!(chars.next(), Some('\''), "unquoted {:?}", word);
+        loop {
+            match chars.next() {
+                Some('\'') => {
+                    if chars.peek() == Some(&'\'') {
+                        value.push('\'');
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                },
+                Some(c) => value.push(c),
+                None => panic!("unterminated quote in {:?}", word),
+            }
+        }
+        assert_eq!(chars.next(), None, "trailing characters in {:?}", word);
+
+        value
+    }
+
+    /// Reads the rest of a `set` line the way a batch file does, refusing anything special that isn't escaped.
+    fn unescape_cmd(escaped: &str) -> String {
+        let mut value = String::new();
+        let mut chars = escaped.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '^' => value.push(chars.next().expect("trailing ^")),
+                '%' => {
+                    assert_eq!(chars.next(), Some('%'), "unescaped % in {:?}", escaped);
+                    value.push('%');
+                },
+                '&' | '|' | '<' | '>' | '(' | ')' | '"' | '\n' | '\r' => panic!("unescaped {:?} in {:?}", c, escaped),
+                c => value.push(c),
+            }
+        }
+
+        value
+    }
+
+    /// Reads a value from a `.env` file the way docker-compose does.
+    fn unquote_dotenv(word: &str) -> String {
+        if word.starts_with('\'') {
+            assert!(word.len() >= 2 && word.ends_with('\''), "unterminated quote in {:?}", word);
+            let value = &word[1..word.len() - 1];
+            assert!(!value.contains('\''), "quote inside single quotes in {:?}", word);
+            return value.to_owned();
+        }
+
+        let mut value = String::new();
+        let mut chars = word.chars();
+
+        assert_eq!(chars.next(), Some('"'), "unquoted {:?}", word);
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('$') => panic!("unescaped $ in {:?}", word),
+                Some('\\') => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some(c) => value.push(c),
+                    None => panic!("unterminated quote in {:?}", word),
+                },
+                Some(c) => value.push(c),
+                None => panic!("unterminated quote in {:?}", word),
+            }
+        }
+        assert_eq!(chars.next(), None, "trailing characters in {:?}", word);
+
+        value
+    }
+
+    /// Reads a double quoted value the way systemd does.
+    fn unquote_systemd(word: &str) -> String {
+        let mut value = String::new();
+        let mut chars = word.chars();
+
+        assert_eq!(chars.next(), Some('"'), "unquoted {:?}", word);
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some(c @ '"') | Some(c @ '\\') | Some(c @ '`') | Some(c @ '$') => value.push(c),
+                    Some(c) => { value.push('\\'); value.push(c); },
+                    None => panic!("unterminated quote in {:?}", word),
+                },
+                Some(c) => value.push(c),
+                None => panic!("unterminated quote in {:?}", word),
+            }
+        }
+        assert_eq!(chars.next(), None, "trailing characters in {:?}", word);
+
+        value
+    }
+
+    /// docker takes everything after the `=` as the value.
+    fn read_as_is(word: &str) -> String {
+        word.to_owned()
+    }
+
+    fn strip_prefix<'a>(line: &'a str, prefix: &str) -> &'a str {
+        assert!(line.starts_with(prefix), "{:?} doesn't start with {:?}", line, prefix);
+        &line[prefix.len()..]
+    }
+
+    fn strip_suffix<'a>(line: &'a str, suffix: &str) -> &'a str {
+        assert!(line.ends_with(suffix), "{:?} doesn't end with {:?}", line, suffix);
+        &line[..line.len() - suffix.len()]
+    }
+
+    #[test]
+    fn quotes_known_values() {
+        assert_eq!(quote_posix("a'b $(c)"), r"'a'\''b $(c)'");
+        assert_eq!(quote_fish(r"a'b\c $d"), r"'a\'b\\c $d'");
+        assert_eq!(quote_powershell("a'b\u{2019}c $d `e"), "'a''b\u{2019}\u{2019}c $d `e'");
+        assert_eq!(quote_csh("a'b!c\nd $e"), "'a'\\''b\\!c\\\nd $e'");
+        assert_eq!(quote_nushell("a\"b\\c\nd\u{1b} $e"), r#""a\"b\\c\nd\u{1b} $e""#);
+        assert_eq!(quote_elvish("a'b $c"), "'a''b $c'");
+        assert_eq!(escape_cmd("a&b^c%d%\"e"), "a^&b^^c%%d%%^\"e");
+        assert_eq!(quote_dotenv("a\"b $c"), "'a\"b $c'");
+        assert_eq!(quote_dotenv("a'b\n$c"), r#""a'b\n\$c""#);
+        assert_eq!(quote_systemd("a\"b\\c`d $e"), r#""a\"b\\c\`d \$e""#);
+    }
+
+    #[test]
+    fn unknown_format_is_an_error() {
+        assert!(OutputFormat::from_name("tcsh", true).is_ok());
+        assert!(OutputFormat::from_name("ksh", true).is_err());
+    }
+
+    #[test]
+    fn k8s_secret_is_an_error() {
+        let vars = vec![("KEY".to_owned(), "value".to_owned())].into_iter().collect();
+
+        assert!(format_var("KEY", "value", OutputFormat::K8sSecret).is_err());
+        assert!(format_vars(&vars, OutputFormat::K8sSecret).is_err());
+    }
+
+    #[test]
+    fn json_needs_all_the_variables() {
+        assert!(format_var("KEY", "value", OutputFormat::Json).is_err());
+        assert_eq!(format_vars(&vec![("KEY".to_owned(), "a\"b".to_owned())].into_iter().collect(), OutputFormat::Json).unwrap(),
+            "{\"KEY\":\"a\\\"b\"}\n");
+    }
+
+    #[test]
+    fn single_line_formats_refuse_line_breaks() {
+        for &format in &[OutputFormat::Cmd, OutputFormat::DockerEnv, OutputFormat::Systemd] {
+            assert!(format_var("KEY", "a\nb", format).is_err());
+            assert!(format_var("KEY", "a\rb", format).is_err());
+        }
+    }
+
+    /// Formats random values as `KEY`, and checks that reading them back from the line between
+    /// `prefix` and `suffix` gives the same value.
+    fn check_round_trips(rng: &mut StdRng, output_format: OutputFormat, prefix: &str, suffix: &str, unquote: Unquote) {
+        for _ in 0..1000 {
+            let mut value = random_value(rng);
+            if output_format.is_single_line() {
+                value = value.replace(&['\n', '\r'][..], "");
+            }
+
+            let line = format_var("KEY", &value, output_format).unwrap();
+            assert_eq!(unquote(strip_suffix(strip_prefix(&line, prefix), suffix)), value, "{:?} wrote {:?}", output_format, line);
+        }
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let formats: &[(OutputFormat, &str, &str, Unquote)] = &[
+            (OutputFormat::Bash { export: true }, "export KEY=", "", unquote_posix),
+            (OutputFormat::Bash { export: false }, "KEY=", "", unquote_posix),
+            (OutputFormat::Zsh { export: true }, "export KEY=", "", unquote_posix),
+            (OutputFormat::Zsh { export: false }, "KEY=", "", unquote_posix),
+            (OutputFormat::Fish { export: true }, "set -x KEY ", "", unquote_fish),
+            (OutputFormat::Fish { export: false }, "set KEY ", "", unquote_fish),
+            (OutputFormat::Powershell { export: true }, "$env:KEY = ", "", unquote_powershell),
+            (OutputFormat::Powershell { export: false }, "$KEY = ", "", unquote_powershell),
+            (OutputFormat::Csh { export: true }, "setenv KEY ", ";", unquote_csh),
+            (OutputFormat::Csh { export: false }, "set KEY = ", ";", unquote_csh),
+            (OutputFormat::Nushell { export: true }, "$env.KEY = ", "", unquote_nushell),
+            (OutputFormat::Nushell { export: false }, "let KEY = ", "", unquote_nushell),
+            (OutputFormat::Elvish { export: true }, "set-env KEY ", "", unquote_elvish),
+            (OutputFormat::Elvish { export: false }, "var KEY = ", "", unquote_elvish),
+            (OutputFormat::Cmd, "@set KEY=", "", unescape_cmd),
+            (OutputFormat::Dotenv, "KEY=", "", unquote_dotenv),
+            (OutputFormat::DockerEnv, "KEY=", "", read_as_is),
+            (OutputFormat::Systemd, "KEY=", "", unquote_systemd),
+        ];
+
+        let mut rng = seeded_rng();
+
+        for &(output_format, prefix, suffix, unquote) in formats {
+            check_round_trips(&mut rng, output_format, prefix, suffix, unquote);
+        }
+    }
+
+    /// The environment a command started with `env` and `inherit` sees, less what `sh` sets itself.
+    #[cfg(unix)]
+    fn child_env(env: &HashMap<String, String>, inherit: Inherit) -> HashMap<String, String> {
+        let output = build_command(OsStr::new("sh"), &["-c", "env"], env, inherit).output().unwrap();
+        assert!(output.status.success());
+
+        String::from_utf8(output.stdout).unwrap().lines()
+            .filter_map(|line| line.find('=').map(|i| (line[..i].to_owned(), line[i + 1..].to_owned())))
+            .filter(|var| !["PWD", "OLDPWD", "SHLVL", "_"].contains(&&var.0[..]))
+            .collect()
+    }
+
+    #[test]
+    fn credential_process_output_has_the_shape_the_sdks_expect() {
+        use chrono::{DateTime, Utc};
+        use serde_json::Value;
+
+        let expires_at = "2030-01-02T03:04:05Z".parse::<DateTime<Utc>>().unwrap();
+        let creds = AwsCredentials::new("ASIAEXAMPLE", "secret", Some("token".to_owned()), expires_at);
+
+        let output: Value = serde_json::from_str(&format_credential_process(&creds).unwrap()).unwrap();
+        let output = output.as_object().unwrap();
+
+        let mut keys: Vec<&str> = output.keys().map(|k| &k[..]).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["AccessKeyId", "Expiration", "SecretAccessKey", "SessionToken", "Version"]);
+        assert_eq!(output["Version"], Value::from(1));
+        assert_eq!(output["AccessKeyId"], Value::from("ASIAEXAMPLE"));
+        assert_eq!(output["SecretAccessKey"], Value::from("secret"));
+        assert_eq!(output["SessionToken"], Value::from("token"));
+        assert_eq!(DateTime::parse_from_rfc3339(output["Expiration"].as_str().unwrap()).unwrap(), expires_at);
+
+        // long term keys have no token, which the SDKs take from the field being left out
+        let creds = AwsCredentials::new("AKIAEXAMPLE", "secret", None, expires_at);
+        let output: Value = serde_json::from_str(&format_credential_process(&creds).unwrap()).unwrap();
+        assert!(output.get("SessionToken").is_none());
+    }
+
+    #[cfg(unix)]
+    fn exit_status_of(script: &str) -> Result<()> {
+        exit_status_result(process::Command::new("sh").arg("-c").arg(script).status().unwrap())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_exit_the_way_the_child_did() {
+        assert!(exit_status_of("exit 0").is_ok());
+
+        match exit_status_of("exit 3") {
+            Err(ref err @ StsCliError::ChildExited(3)) => assert_eq!(err.exit_code(), 3),
+            result => panic!("expected ChildExited(3), got {:?}", result),
+        }
+
+        match exit_status_of("kill -TERM $$") {
+            Err(ref err @ StsCliError::ChildKilled(15)) => assert_eq!(err.exit_code(), 143),
+            result => panic!("expected ChildKilled(15), got {:?}", result),
+        }
+    }
+
+    #[cfg(unix)]
+    fn session_env() -> HashMap<String, String> {
+        let mut env = HashMap::new();
+        env.insert("AWS_ACCESS_KEY_ID".to_owned(), "ASIAEXAMPLE".to_owned());
+        env.insert("AWS_SECRET_ACCESS_KEY".to_owned(), "secret".to_owned());
+        env.insert("AWS_SESSION_TOKEN".to_owned(), "token".to_owned());
+        env
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_dont_see_other_profiles() {
+        env::set_var("AWS_PROFILE", "other");
+        env::set_var("AWS_DEFAULT_PROFILE", "other");
+        env::set_var("STSCLI_TEST_INHERITED", "inherited");
+
+        let child = child_env(&session_env(), Inherit::Scrubbed);
+
+        assert!(!child.contains_key("AWS_PROFILE"));
+        assert!(!child.contains_key("AWS_DEFAULT_PROFILE"));
+        assert_eq!(child.get("STSCLI_TEST_INHERITED").map(|s| &s[..]), Some("inherited"));
+        assert_eq!(child.get("AWS_ACCESS_KEY_ID").map(|s| &s[..]), Some("ASIAEXAMPLE"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn isolated_commands_only_get_the_allowlist_and_the_session() {
+        env::set_var("AWS_PROFILE", "other");
+        env::set_var("STSCLI_TEST_INHERITED", "inherited");
+
+        let env = session_env();
+        let child = child_env(&env, Inherit::Isolated);
+
+        for key in child.keys() {
+            assert!(ISOLATED_VARS.contains(&&key[..]) || env.contains_key(key), "{} was passed on", key);
+        }
+        assert_eq!(child.get("PATH"), env::var("PATH").ok().as_ref());
+        assert_eq!(child.get("AWS_SESSION_TOKEN").map(|s| &s[..]), Some("token"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn given_vars_override_inherited_ones() {
+        env::set_var("STSCLI_TEST_OVERRIDDEN", "inherited");
+
+        let mut env = session_env();
+        env.insert("STSCLI_TEST_OVERRIDDEN".to_owned(), "given".to_owned());
+        env.insert("AWS_PROFILE".to_owned(), "given".to_owned());
+
+        for &inherit in &[Inherit::Scrubbed, Inherit::Isolated] {
+            let child = child_env(&env, inherit);
+
+            assert_eq!(child.get("STSCLI_TEST_OVERRIDDEN").map(|s| &s[..]), Some("given"));
+            assert_eq!(child.get("AWS_PROFILE").map(|s| &s[..]), Some("given"));
+        }
+    }
+
+    /// Checks the quoting against a real shell, if it is installed, by having it print the value back.
+    #[cfg(unix)]
+    fn check_with_shell(shell: &[&str], output_format: OutputFormat, print_value: &str) {
+        use std::io;
+        use std::process::Command;
+
+        let mut rng = seeded_rng();
+
+        for _ in 0..50 {
+            let value = random_value(&mut rng);
+            let script = format!("{}\n{}", format_var("KEY", &value, output_format).unwrap(), print_value);
+
+            let output = match Command::new(shell[0]).args(&shell[1..]).arg(&script).output() {
+                Ok(output) => output,
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                    println!("{} isn't installed, skipping", shell[0]);
+                    return;
+                },
+                Err(err) => panic!("can't run {}: {}", shell[0], err),
+            };
+
+            assert!(output.status.success(), "{:?} failed in {}: {}", script, shell[0], String::from_utf8_lossy(&output.stderr));
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), value, "{} read {:?} differently", shell[0], script);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sh_reads_values_back() {
+        check_with_shell(&["sh", "-c"], OutputFormat::Bash { export: false }, "printf %s \"$KEY\"");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn bash_reads_values_back() {
+        check_with_shell(&["bash", "-c"], OutputFormat::Bash { export: true }, "printf %s \"$KEY\"");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fish_reads_values_back() {
+        check_with_shell(&["fish", "-c"], OutputFormat::Fish { export: true }, "printf %s \"$KEY\"");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn powershell_reads_values_back() {
+        check_with_shell(&["pwsh", "-NoProfile", "-NonInteractive", "-Command"], OutputFormat::Powershell { export: true },
+            "[Console]::Out.Write($env:KEY)");
+    }
 }
@@ -0,0 +1,27 @@
+use chrono::Utc;
+use rusoto_core::{AwsCredentials, CredentialsError, ProvideAwsCredentials};
+
+/// Provides a fixed set of credentials, such as the result of a previous AssumeRole call.
+#[derive(Debug, Clone)]
+pub struct FixedCredentialsProvider {
+    credentials: AwsCredentials,
+}
+
+impl FixedCredentialsProvider {
+    pub fn new(credentials: AwsCredentials) -> FixedCredentialsProvider {
+        FixedCredentialsProvider {
+            credentials: credentials,
+        }
+    }
+
+    /// Placeholder credentials for clients whose requests are sent unsigned.
+    pub fn anonymous() -> FixedCredentialsProvider {
+        FixedCredentialsProvider::new(AwsCredentials::new("", "", None, Utc::now()))
+    }
+}
+
+impl ProvideAwsCredentials for FixedCredentialsProvider {
+    fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        Ok(self.credentials.clone())
+    }
+}
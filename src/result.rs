@@ -34,6 +34,17 @@ quick_error! {
             cause(err)
         }
 
+        SourceProfileLoop(chain: String) {
+            description("source_profile loop")
+            display("source_profile loop: {}", chain)
+        }
+
+        TokenCodeNeeded(serial_number: String) {
+            description("MFA token code needed")
+            display("an MFA token code for {} is needed, and stscli can't ask for one in the background. \
+                Use --token-code-command to get codes from another program", serial_number)
+        }
+
         ProcessKilled {
             description("process killed")
             display("process killed")
@@ -44,6 +55,11 @@ quick_error! {
             display("child exited: {}", code)
         }
 
+        ChildKilled(signal: i32) {
+            description("child killed")
+            display("child killed by signal {}", signal)
+        }
+
         JsonEncoderError(err: serde_json::Error) {
             from()
             description("json encoder error")
@@ -72,6 +88,41 @@ quick_error! {
             cause(err)
         }
 
+        AssumeRoleWithWebIdentityError(err: rusoto_sts::AssumeRoleWithWebIdentityError) {
+            from()
+            description("STS AssumeRoleWithWebIdentityError")
+            display("STS AssumeRoleWithWebIdentityError: {}", err)
+            cause(err)
+        }
+
+        AssumeRoleWithSAMLError(err: rusoto_sts::AssumeRoleWithSAMLError) {
+            from()
+            description("STS AssumeRoleWithSAMLError")
+            display("STS AssumeRoleWithSAMLError: {}", err)
+            cause(err)
+        }
+
+        GetCallerIdentityError(err: rusoto_sts::GetCallerIdentityError) {
+            from()
+            description("STS GetCallerIdentityError")
+            display("STS GetCallerIdentityError: {}", err)
+            cause(err)
+        }
+
+        DecodeAuthorizationMessageError(err: rusoto_sts::DecodeAuthorizationMessageError) {
+            from()
+            description("STS DecodeAuthorizationMessageError")
+            display("STS DecodeAuthorizationMessageError: {}", err)
+            cause(err)
+        }
+
+        GetFederationTokenError(err: rusoto_sts::GetFederationTokenError) {
+            from()
+            description("STS GetFederationTokenError")
+            display("STS GetFederationTokenError: {}", err)
+            cause(err)
+        }
+
         TlsError(err: rusoto_core::TlsError) {
             from()
             description("TLS Error")
@@ -81,4 +132,23 @@ quick_error! {
     }
 }
 
+impl StsCliError {
+    /// Prefixes the description of a plain error with where it happened.
+    pub fn with_context<S>(self, context: S) -> StsCliError where S: AsRef<str> {
+        match self {
+            StsCliError::Error(descr) => StsCliError::Error(format!("{}: {}", context.as_ref(), descr)),
+            err => err,
+        }
+    }
+
+    /// The status stscli exits with, which for a command is the command's own, as a shell would give it.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            StsCliError::ChildExited(code) => code,
+            StsCliError::ChildKilled(signal) => 128 + signal,
+            _ => 1,
+        }
+    }
+}
+
 pub type Result<T> = result::Result<T, StsCliError>;
@@ -0,0 +1,237 @@
+use rustc_serialize::base64::FromBase64;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use xml::reader::{EventReader, XmlEvent};
+use result::*;
+
+pub const ROLE_ATTRIBUTE: &'static str = "https://aws.amazon.com/SAML/Attributes/Role";
+
+/// A role the assertion allows, and the identity provider to assume it through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamlRole {
+    pub role_arn: String,
+    pub principal_arn: String,
+}
+
+impl SamlRole {
+    /// Parses a value of the Role attribute, which is the two ARNs separated by a comma, in either order.
+    fn parse(value: &str) -> Result<SamlRole> {
+        let arns: Vec<&str> = value.split(',').map(|s| s.trim()).collect();
+
+        if arns.len() == 2 {
+            let role = arns.iter().find(|arn| arn.contains(":role/"));
+            let principal = arns.iter().find(|arn| arn.contains(":saml-provider/"));
+
+            if let (Some(role), Some(principal)) = (role, principal) {
+                return Ok(SamlRole {
+                    role_arn: role.to_string(),
+                    principal_arn: principal.to_string(),
+                });
+            }
+        }
+
+        Err(StsCliError::Error(format!("invalid role in SAML assertion: {}", value)))
+    }
+
+    fn role_name(&self) -> &str {
+        self.role_arn.rsplit('/').next().unwrap_or(&self.role_arn)
+    }
+}
+
+/// Reads a base64 encoded SAMLResponse from a file, or from stdin if no file or `-` is given.
+pub fn read_assertion(path: Option<&Path>) -> Result<String> {
+    let mut assertion = String::new();
+
+    match path {
+        Some(path) if path != Path::new("-") => {
+            let mut file = try!(File::open(path)
+                .map_err(|e| StsCliError::Error(format!("can't open SAML assertion file {}: {}", path.display(), e))));
+            try!(file.read_to_string(&mut assertion));
+        },
+        _ => { try!(io::stdin().read_to_string(&mut assertion)); }
+    }
+
+    // the response is often copied out of a browser with line breaks in it
+    let assertion: String = assertion.chars().filter(|c| !c.is_whitespace()).collect();
+
+    if assertion.is_empty() {
+        return Err(StsCliError::Error("the SAML assertion is empty".to_owned()));
+    }
+
+    Ok(assertion)
+}
+
+/// Lists the roles in the assertion's `https://aws.amazon.com/SAML/Attributes/Role` attribute.
+pub fn roles(assertion: &str) -> Result<Vec<SamlRole>> {
+    let xml = try!(assertion.from_base64()
+        .map_err(|e| StsCliError::Error(format!("the SAML assertion is not valid base64: {}", e))));
+
+    let mut roles = vec![];
+    let mut in_role_attribute = false;
+    let mut value: Option<String> = None;
+
+    for event in EventReader::new(&xml[..]) {
+        match try!(event.map_err(|e| StsCliError::Error(format!("the SAML assertion is not valid XML: {}", e)))) {
+            XmlEvent::StartElement { ref name, ref attributes, .. } if name.local_name == "Attribute" => {
+                in_role_attribute = attributes.iter().any(|a| a.name.local_name == "Name" && a.value == ROLE_ATTRIBUTE);
+            },
+            XmlEvent::StartElement { ref name, .. } if in_role_attribute && name.local_name == "AttributeValue" => {
+                value = Some(String::new());
+            },
+            XmlEvent::Characters(ref text) => {
+                if let Some(ref mut value) = value {
+                    value.push_str(text);
+                }
+            },
+            XmlEvent::EndElement { ref name } if name.local_name == "AttributeValue" => {
+                if let Some(value) = value.take() {
+                    roles.push(try!(SamlRole::parse(&value)));
+                }
+            },
+            XmlEvent::EndElement { ref name } if name.local_name == "Attribute" => {
+                in_role_attribute = false;
+            },
+            _ => {}
+        }
+    }
+
+    if roles.is_empty() {
+        return Err(StsCliError::Error(format!("the SAML assertion has no {} attribute", ROLE_ATTRIBUTE)));
+    }
+
+    Ok(roles)
+}
+
+/// Picks the role given by ARN or name, the only role there is, or asks which one to use.
+pub fn choose_role(roles: &[SamlRole], role: Option<&str>) -> Result<SamlRole> {
+    if let Some(role) = role {
+        return roles.iter()
+            .find(|r| r.role_arn == role || r.role_name() == role)
+            .cloned()
+            .ok_or_else(|| StsCliError::Error(format!("the SAML assertion doesn't allow role {}. It allows: {}",
+                role, roles.iter().map(|r| r.role_arn.as_str()).collect::<Vec<&str>>().join(", "))));
+    }
+
+    if roles.len() == 1 {
+        return Ok(roles[0].clone());
+    }
+
+    prompt_role(roles)
+}
+
+/// Asks on the controlling terminal, since stdin may be the assertion and stdout may be captured.
+#[cfg(unix)]
+fn prompt_role(roles: &[SamlRole]) -> Result<SamlRole> {
+    use std::fs::OpenOptions;
+
+    let tty = try!(OpenOptions::new().read(true).write(true).open("/dev/tty")
+        .map_err(|e| StsCliError::Error(format!(
+            "the SAML assertion allows several roles but there is no terminal to ask which one to use ({}). Use --role", e))));
+
+    let reader = try!(tty.try_clone());
+
+    ask_role(roles, io::BufReader::new(reader), tty)
+}
+
+/// Stdin may be the assertion, so this asks on the console, which Windows opens as `CONIN$` and `CONOUT$`.
+#[cfg(not(unix))]
+fn prompt_role(roles: &[SamlRole]) -> Result<SamlRole> {
+    use std::fs::OpenOptions;
+
+    let no_console = |e: io::Error| StsCliError::Error(format!(
+        "the SAML assertion allows several roles but there is no console to ask which one to use ({}). Use --role", e));
+
+    let input = try!(OpenOptions::new().read(true).write(true).open("CONIN$").map_err(&no_console));
+    let output = try!(OpenOptions::new().write(true).open("CONOUT$").map_err(&no_console));
+
+    ask_role(roles, io::BufReader::new(input), output)
+}
+
+fn ask_role<R: io::BufRead, W: Write>(roles: &[SamlRole], mut input: R, mut output: W) -> Result<SamlRole> {
+    for (i, role) in roles.iter().enumerate() {
+        try!(writeln!(output, "{}) {} (via {})", i + 1, role.role_arn, role.principal_arn));
+    }
+
+    loop {
+        try!(write!(output, "Role to assume [1-{}]: ", roles.len()));
+        try!(output.flush());
+
+        let mut line = String::new();
+        if try!(input.read_line(&mut line)) == 0 {
+            return Err(StsCliError::Error("no role was chosen".to_owned()));
+        }
+
+        match line.trim().parse::<usize>() {
+            Ok(n) if n >= 1 && n <= roles.len() => return Ok(roles[n - 1].clone()),
+            _ => try!(writeln!(output, "enter a number between 1 and {}", roles.len())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::base64::{ToBase64, STANDARD};
+    use std::io::Cursor;
+    use super::*;
+
+    const ADMIN: &'static str = "arn:aws:iam::123456789012:role/Admin";
+    const READ_ONLY: &'static str = "arn:aws:iam::123456789012:role/ReadOnly";
+    const PROVIDER: &'static str = "arn:aws:iam::123456789012:saml-provider/idp";
+
+    fn assertion(values: &[String]) -> String {
+        let values: String = values.iter().map(|v| format!("<saml:AttributeValue>{}</saml:AttributeValue>", v)).collect();
+
+        format!(r#"<samlp:Response xmlns:samlp="urn:oasis:names:tc:SAML:2.0:protocol" xmlns:saml="urn:oasis:names:tc:SAML:2.0:assertion">
+            <saml:Assertion><saml:AttributeStatement>
+                <saml:Attribute Name="https://aws.amazon.com/SAML/Attributes/RoleSessionName"><saml:AttributeValue>alice</saml:AttributeValue></saml:Attribute>
+                <saml:Attribute Name="{}">{}</saml:Attribute>
+            </saml:AttributeStatement></saml:Assertion>
+        </samlp:Response>"#, ROLE_ATTRIBUTE, values).as_bytes().to_base64(STANDARD)
+    }
+
+    fn roles_in(values: &[String]) -> Vec<SamlRole> {
+        roles(&assertion(values)).unwrap()
+    }
+
+    #[test]
+    fn parses_roles_in_either_order() {
+        let roles = roles_in(&[format!("{},{}", ADMIN, PROVIDER), format!("{}, {}", PROVIDER, READ_ONLY)]);
+
+        assert_eq!(roles, vec![
+            SamlRole { role_arn: ADMIN.to_owned(), principal_arn: PROVIDER.to_owned() },
+            SamlRole { role_arn: READ_ONLY.to_owned(), principal_arn: PROVIDER.to_owned() },
+        ]);
+    }
+
+    #[test]
+    fn rejects_bad_assertions() {
+        assert!(roles("not base64!").is_err());
+        assert!(roles(&b"<unclosed>".to_base64(STANDARD)).is_err());
+        assert!(roles(&assertion(&[])).is_err());
+        assert!(roles(&assertion(&[ADMIN.to_owned()])).is_err());
+    }
+
+    #[test]
+    fn chooses_by_arn_name_or_only_role() {
+        let roles = roles_in(&[format!("{},{}", ADMIN, PROVIDER), format!("{},{}", READ_ONLY, PROVIDER)]);
+
+        assert_eq!(choose_role(&roles, Some(READ_ONLY)).unwrap().role_arn, READ_ONLY);
+        assert_eq!(choose_role(&roles, Some("Admin")).unwrap().role_arn, ADMIN);
+        assert!(choose_role(&roles, Some("Billing")).is_err());
+        assert_eq!(choose_role(&roles[..1], None).unwrap().role_arn, ADMIN);
+    }
+
+    #[test]
+    fn asks_until_a_valid_number_is_entered() {
+        let roles = roles_in(&[format!("{},{}", ADMIN, PROVIDER), format!("{},{}", READ_ONLY, PROVIDER)]);
+        let mut output = vec![];
+
+        let role = ask_role(&roles, Cursor::new("7\nx\n2\n"), &mut output).unwrap();
+
+        assert_eq!(role.role_arn, READ_ONLY);
+        assert!(String::from_utf8(output).unwrap().contains("enter a number between 1 and 2"));
+        assert!(ask_role(&roles, Cursor::new(""), vec![]).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use chrono::{Duration, Utc};
+use rand::Rng;
+use rand::os::OsRng;
+use rusoto_core::AwsCredentials;
+use serde_json;
+use std::collections::{BTreeMap, HashMap};
+use std::net::TcpListener;
+use std::sync::Mutex;
+use std::thread;
+use config::Config;
+use http;
+use result::*;
+
+const SECRET_LENGTH: usize = 32;
+
+/// The JSON the AWS SDKs expect from a container credentials endpoint.
+#[derive(Debug, Serialize)]
+struct ContainerCredentials<'a> {
+    #[serde(rename = "AccessKeyId")]
+    access_key_id: &'a str,
+    #[serde(rename = "SecretAccessKey")]
+    secret_access_key: &'a str,
+    #[serde(rename = "Token", skip_serializing_if = "Option::is_none")]
+    token: Option<&'a str>,
+    #[serde(rename = "Expiration")]
+    expiration: String,
+}
+
+/// Holds the latest credentials, fetching new ones when they are within the cache margin of expiring.
+pub struct RefreshingCredentials {
+    config: Config,
+    fetch: fn(&Config) -> Result<AwsCredentials>,
+    margin: Duration,
+    current: Mutex<Option<AwsCredentials>>,
+}
+
+impl RefreshingCredentials {
+    pub fn new(config: &Config, fetch: fn(&Config) -> Result<AwsCredentials>, initial: Option<AwsCredentials>) -> RefreshingCredentials {
+        RefreshingCredentials {
+            config: config.clone(),
+            fetch: fetch,
+            margin: Duration::seconds(config.cache_margin),
+            current: Mutex::new(initial),
+        }
+    }
+
+    pub fn credentials(&self) -> Result<AwsCredentials> {
+        let mut current = self.current.lock().unwrap();
+
+        if let Some(ref creds) = *current {
+            if *creds.expires_at() > Utc::now() + self.margin {
+                return Ok(creds.clone());
+            }
+        }
+
+        debug!("refreshing served credentials");
+        let creds = try!((self.fetch)(&self.config));
+        *current = Some(creds.clone());
+
+        Ok(creds)
+    }
+}
+
+/// A loopback endpoint that serves credentials the way ECS does for containers.
+///
+/// Requests must use a random path and present a random token, so other local
+/// users can't read the credentials just by finding the port.
+pub struct CredentialsServer {
+    pub url: String,
+    pub token: String,
+    thread: thread::JoinHandle<()>,
+}
+
+impl CredentialsServer {
+    pub fn start(port: u16, credentials: RefreshingCredentials) -> Result<CredentialsServer> {
+        let path = format!("/{}", try!(random_string()));
+        let token = try!(random_string());
+
+        let listener = try!(TcpListener::bind(("127.0.0.1", port)));
+        let url = format!("http://{}{}", try!(listener.local_addr()), path);
+
+        let expected_token = token.clone();
+        let thread = http::serve(listener, move |request| {
+            if request.path != path {
+                return http::Response::text(404, "not found");
+            }
+
+            if request.header("Authorization") != Some(&expected_token) {
+                return http::Response::text(401, "unauthorized");
+            }
+
+            if request.method != "GET" {
+                return http::Response::text(405, "method not allowed");
+            }
+
+            match credentials.credentials().and_then(|creds| container_credentials_json(&creds)) {
+                Ok(body) => http::Response::json(200, body),
+                Err(err) => {
+                    warn!("failed to get credentials to serve: {}", err);
+                    let mut error = BTreeMap::new();
+                    error.insert("Code", "CredentialsError".to_owned());
+                    error.insert("Message", err.to_string());
+                    http::Response::json(500, serde_json::to_string(&error).unwrap_or_default())
+                },
+            }
+        });
+
+        Ok(CredentialsServer {
+            url: url,
+            token: token,
+            thread: thread,
+        })
+    }
+
+    /// The variables that point the AWS SDKs at this server.
+    pub fn vars(&self) -> HashMap<String, String> {
+        let mut vars = HashMap::new();
+
+        vars.insert("AWS_CONTAINER_CREDENTIALS_FULL_URI".to_owned(), self.url.clone());
+        vars.insert("AWS_CONTAINER_AUTHORIZATION_TOKEN".to_owned(), self.token.clone());
+
+        vars
+    }
+
+    /// Serves requests until the process is killed.
+    pub fn wait(self) -> Result<()> {
+        self.thread.join().map_err(|_| StsCliError::Error("the credentials server stopped".to_owned()))
+    }
+}
+
+fn container_credentials_json(creds: &AwsCredentials) -> Result<String> {
+    Ok(try!(serde_json::to_string(&ContainerCredentials {
+        access_key_id: creds.aws_access_key_id(),
+        secret_access_key: creds.aws_secret_access_key(),
+        token: creds.token().as_ref().map(|s| s.as_str()),
+        expiration: creds.expires_at().to_rfc3339(),
+    })))
+}
+
+/// A random alphanumeric string for use as a secret, such as a path or token.
+pub fn random_string() -> Result<String> {
+    let mut rng = try!(OsRng::new());
+    Ok(rng.gen_ascii_chars().take(SECRET_LENGTH).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+    use rusoto_core::AwsCredentials;
+    use serde_json;
+    use serde_json::Value;
+    use config::Config;
+    use http;
+    use result::*;
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            cache_margin: 300,
+            ..Default::default()
+        }
+    }
+
+    fn creds(access_key_id: &str, expires_in: Duration) -> AwsCredentials {
+        AwsCredentials::new(access_key_id, "SECRET", Some("TOKEN".to_owned()), Utc::now() + expires_in)
+    }
+
+    fn fetch_new(_config: &Config) -> Result<AwsCredentials> {
+        Ok(creds("NEW", Duration::hours(1)))
+    }
+
+    fn fetch_fails(_config: &Config) -> Result<AwsCredentials> {
+        Err(StsCliError::Error("an MFA token code is needed".to_owned()))
+    }
+
+    #[test]
+    fn refreshes_only_near_expiry() {
+        let fresh = RefreshingCredentials::new(&config(), fetch_new, Some(creds("OLD", Duration::hours(1))));
+        assert_eq!(fresh.credentials().unwrap().aws_access_key_id(), "OLD");
+
+        let expiring = RefreshingCredentials::new(&config(), fetch_new, Some(creds("OLD", Duration::seconds(60))));
+        assert_eq!(expiring.credentials().unwrap().aws_access_key_id(), "NEW");
+        assert_eq!(expiring.credentials().unwrap().aws_access_key_id(), "NEW");
+    }
+
+    #[test]
+    fn refresh_errors_are_returned() {
+        let expiring = RefreshingCredentials::new(&config(), fetch_fails, Some(creds("OLD", Duration::seconds(60))));
+
+        assert!(expiring.credentials().is_err());
+    }
+
+    fn get(url: &str, token: Option<&str>) -> (String, String) {
+        let (address, path) = url["http://".len()..].split_at(url["http://".len()..].find('/').unwrap());
+        let authorization = token.map(|t| format!("Authorization: {}\r\n", t)).unwrap_or_default();
+
+        let response = http::send(address, format!("GET {} HTTP/1.1\r\nHost: {}\r\n{}\r\n", path, address, authorization).as_bytes());
+
+        let status_end = response.find("\r\n").unwrap();
+        let body_start = response.find("\r\n\r\n").unwrap() + 4;
+        (response[..status_end].to_owned(), response[body_start..].to_owned())
+    }
+
+    #[test]
+    fn serves_credentials_with_the_token() {
+        let server = CredentialsServer::start(0, RefreshingCredentials::new(&config(), fetch_new, Some(creds("OLD", Duration::hours(1))))).unwrap();
+
+        let (status, body) = get(&server.url, Some(&server.token));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+
+        let json: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json.pointer("/AccessKeyId").and_then(|v| v.as_str()), Some("OLD"));
+        assert_eq!(json.pointer("/Token").and_then(|v| v.as_str()), Some("TOKEN"));
+
+        assert_eq!(get(&server.url, None).0, "HTTP/1.1 401 Unauthorized");
+        assert_eq!(get(&server.url, Some("wrong")).0, "HTTP/1.1 401 Unauthorized");
+
+        let other_path = format!("{}x", server.url);
+        assert_eq!(get(&other_path, Some(&server.token)).0, "HTTP/1.1 404 Not Found");
+    }
+
+    #[test]
+    fn reports_refresh_failures_as_json() {
+        let server = CredentialsServer::start(0, RefreshingCredentials::new(&config(), fetch_fails, None)).unwrap();
+
+        let (status, body) = get(&server.url, Some(&server.token));
+        assert_eq!(status, "HTTP/1.1 500 Internal Server Error");
+
+        let json: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json.pointer("/Code").and_then(|v| v.as_str()), Some("CredentialsError"));
+    }
+}
@@ -0,0 +1,39 @@
+use rusoto_core::AwsCredentials;
+use rusoto_sts::{AssumedRoleUser, Credentials, NewAwsCredsForStsCreds};
+use result::*;
+
+/// Temporary credentials, and the role they were issued for if any.
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub credentials: AwsCredentials,
+    /// The role that was asked for.
+    pub role_arn: Option<String>,
+    /// The ARN of the assumed role session, which names the session as well as the role.
+    pub assumed_role_arn: Option<String>,
+}
+
+impl Session {
+    pub fn new(credentials: AwsCredentials) -> Session {
+        Session {
+            credentials: credentials,
+            role_arn: None,
+            assumed_role_arn: None,
+        }
+    }
+
+    /// Builds a session from the parts of an STS response, which are all optional.
+    pub fn from_sts(operation: &str, credentials: Option<Credentials>, assumed_role_user: Option<AssumedRoleUser>, role_arn: Option<String>) -> Result<Session> {
+        let sts_creds = try!(credentials.ok_or(StsCliError::Error(format!("STS {} did not return any credentials", operation))));
+
+        Ok(Session {
+            credentials: try!(AwsCredentials::new_for_credentials(sts_creds)),
+            role_arn: role_arn,
+            assumed_role_arn: assumed_role_user.map(|user| user.arn),
+        })
+    }
+
+    /// When the credentials expire, in ISO 8601 format.
+    pub fn expiration(&self) -> String {
+        self.credentials.expires_at().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+    }
+}
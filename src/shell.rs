@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use files;
+use print::quote_posix;
+use result::*;
+use serve;
+
+/// Set in the environment of shells started by stscli, so nesting them can be detected.
+pub const ACTIVE_VAR: &'static str = "STSCLI_ACTIVE";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ShellKind {
+    Bash,
+    Zsh,
+    Fish,
+    /// A shell whose prompt we don't know how to change.
+    Other,
+}
+
+impl ShellKind {
+    pub fn detect(program: &Path) -> ShellKind {
+        match program.file_name().and_then(|name| name.to_str()) {
+            Some("bash") => ShellKind::Bash,
+            Some("zsh") => ShellKind::Zsh,
+            Some("fish") => ShellKind::Fish,
+            _ => ShellKind::Other,
+        }
+    }
+}
+
+/// An interactive shell to run, with the arguments and files that put the marker in its prompt.
+///
+/// The marker shows `STSCLI_PROFILE` and the time left until the session expires.
+/// Any files are removed when this is dropped.
+#[derive(Debug)]
+pub struct Shell {
+    pub program: PathBuf,
+    pub kind: ShellKind,
+    pub args: Vec<OsString>,
+    pub env: HashMap<String, String>,
+    temp_dir: Option<PathBuf>,
+}
+
+impl Shell {
+    pub fn new(program: PathBuf, expires_at: i64) -> Result<Shell> {
+        let kind = ShellKind::detect(&program);
+
+        let mut shell = Shell {
+            program: program,
+            kind: kind,
+            args: vec![],
+            env: HashMap::new(),
+            temp_dir: None,
+        };
+
+        match kind {
+            ShellKind::Bash => {
+                let dir = try!(shell.create_temp_dir());
+                let rc_file = dir.join("bashrc");
+
+                try!(files::write_private_file_atomic(&rc_file, bash_rc(expires_at).as_bytes()));
+
+                shell.args.push(OsString::from("--rcfile"));
+                shell.args.push(rc_file.into_os_string());
+                shell.args.push(OsString::from("-i"));
+            },
+            ShellKind::Zsh => {
+                // zsh reads its startup files from ZDOTDIR, so ours load the user's and then change the prompt
+                let dir = try!(shell.create_temp_dir());
+                let original_dir = env::var("ZDOTDIR").ok()
+                    .or_else(|| env::home_dir().map(|home| home.to_string_lossy().into_owned()))
+                    .unwrap_or(".".to_owned());
+
+                try!(files::write_private_file_atomic(&dir.join(".zshenv"), zsh_env(&original_dir).as_bytes()));
+                try!(files::write_private_file_atomic(&dir.join(".zshrc"), zsh_rc(&original_dir, expires_at).as_bytes()));
+
+                shell.env.insert("ZDOTDIR".to_owned(), dir.to_string_lossy().into_owned());
+                shell.args.push(OsString::from("-i"));
+            },
+            ShellKind::Fish => {
+                shell.args.push(OsString::from("--init-command"));
+                shell.args.push(OsString::from(fish_init(expires_at)));
+            },
+            ShellKind::Other => {},
+        }
+
+        Ok(shell)
+    }
+
+    fn create_temp_dir(&mut self) -> Result<PathBuf> {
+        let dir = try!(create_temp_dir_in(&env::temp_dir()));
+        self.temp_dir = Some(dir.clone());
+
+        Ok(dir)
+    }
+}
+
+impl Drop for Shell {
+    fn drop(&mut self) {
+        if let Some(ref dir) = self.temp_dir {
+            if let Err(err) = fs::remove_dir_all(dir) {
+                warn!("failed to remove {}: {}", dir.display(), err);
+            }
+        }
+    }
+}
+
+/// Creates a directory with a random name, so other users can't create it first and plant files in it.
+fn create_temp_dir_in(parent: &Path) -> Result<PathBuf> {
+    let dir = parent.join(format!("stscli-shell-{}", try!(serve::random_string())));
+
+    try!(files::create_private_dir(&dir));
+
+    Ok(dir)
+}
+
+/// Prints the time left, e.g. `1h5m`, for sh-like shells.
+fn sh_time_left(expires_at: i64) -> String {
+    format!("__stscli_time_left() {{
+    local left=$(( ({} - $(date +%s)) / 60 ))
+    if [ \"$left\" -ge 60 ]; then echo \"$((left / 60))h$((left % 60))m\"
+    elif [ \"$left\" -gt 0 ]; then echo \"${{left}}m\"
+    else echo expired; fi
+}}
+", expires_at)
+}
+
+fn bash_rc(expires_at: i64) -> String {
+    format!("[ -f ~/.bashrc ] && . ~/.bashrc
+{}PS1='[stscli:$STSCLI_PROFILE $(__stscli_time_left)] '\"$PS1\"
+", sh_time_left(expires_at))
+}
+
+fn zsh_env(original_dir: &str) -> String {
+    format!("[ -f {0}/.zshenv ] && . {0}/.zshenv
+", quote_posix(original_dir))
+}
+
+fn zsh_rc(original_dir: &str, expires_at: i64) -> String {
+    format!("ZDOTDIR={0}
+[ -f {0}/.zshrc ] && . {0}/.zshrc
+{1}setopt prompt_subst
+PROMPT='[stscli:$STSCLI_PROFILE $(__stscli_time_left)] '\"$PROMPT\"
+", quote_posix(original_dir), sh_time_left(expires_at))
+}
+
+fn fish_init(expires_at: i64) -> String {
+    format!("function __stscli_time_left
+    set -l left (math --scale=0 \"({} - \"(date +%s)\") / 60\")
+    if test $left -ge 60
+        echo (math --scale=0 \"$left / 60\")\"h\"(math \"$left % 60\")\"m\"
+    else if test $left -gt 0
+        echo $left\"m\"
+    else
+        echo expired
+    end
+end
+functions -c fish_prompt __stscli_fish_prompt
+function fish_prompt
+    echo -n \"[stscli:$STSCLI_PROFILE \"(__stscli_time_left)\"] \"
+    __stscli_fish_prompt
+end
+", expires_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use super::*;
+
+    #[test]
+    fn temp_dirs_have_unpredictable_names() {
+        let parent = env::temp_dir().join(format!("stscli-shell-test-{}", process::id()));
+        let _ = fs::remove_dir_all(&parent);
+        files::create_private_dir_all(&parent).unwrap();
+
+        let first = create_temp_dir_in(&parent).unwrap();
+        let second = create_temp_dir_in(&parent).unwrap();
+
+        assert!(first != second);
+        assert!(first.is_dir() && second.is_dir());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&first).unwrap().permissions().mode() & 0o777, 0o700);
+        }
+
+        fs::remove_dir_all(&parent).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+pub use self::imp::*;
+
+#[cfg(unix)]
+mod imp {
+    use libc;
+    use std::mem;
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static CHILD_PID: AtomicUsize = AtomicUsize::new(0);
+    /// A signal that came before the child was started, to pass on once it has been.
+    static PENDING_SIGNAL: AtomicUsize = AtomicUsize::new(0);
+
+    /// Sent by the terminal to its whole foreground process group.
+    const TERMINAL_SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGHUP];
+
+    extern "C" fn forward_signal(signal: libc::c_int) {
+        let pid = CHILD_PID.load(Ordering::SeqCst);
+
+        if pid != 0 {
+            unsafe { libc::kill(pid as libc::pid_t, signal) };
+            return;
+        }
+
+        PENDING_SIGNAL.store(signal as usize, Ordering::SeqCst);
+
+        // the child may have been set in the meantime, and whichever side takes the signal sends it
+        let pid = CHILD_PID.load(Ordering::SeqCst);
+        if pid != 0 && PENDING_SIGNAL.swap(0, Ordering::SeqCst) != 0 {
+            unsafe { libc::kill(pid as libc::pid_t, signal) };
+        }
+    }
+
+    /// Passes SIGTERM, and SIGINT and SIGHUP unless the terminal sends them to the child itself, on to a
+    /// child process until dropped.
+    pub struct Forwarding {
+        previous: Vec<(libc::c_int, libc::sigaction)>,
+    }
+
+    impl Forwarding {
+        /// Starts passing signals on to the child, including any that came before it was started.
+        pub fn set_child(&self, pid: u32) {
+            CHILD_PID.store(pid as usize, Ordering::SeqCst);
+
+            let signal = PENDING_SIGNAL.swap(0, Ordering::SeqCst);
+            if signal != 0 {
+                unsafe { libc::kill(pid as libc::pid_t, signal as libc::c_int) };
+            }
+        }
+    }
+
+    /// Installs the handlers before the child is started, so a signal that comes while it is being
+    /// started isn't lost. The child must share stscli's process group, as `Command` children do by default.
+    pub fn forward() -> Forwarding {
+        CHILD_PID.store(0, Ordering::SeqCst);
+        PENDING_SIGNAL.store(0, Ordering::SeqCst);
+
+        let forward = forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+
+        // a Ctrl-C already reaches every process in the foreground group, so forwarding it would
+        // make the child get it twice. stscli just keeps waiting for the child to exit
+        let terminal_handler = if in_foreground_process_group() { libc::SIG_IGN } else { forward };
+
+        let mut previous = vec![(libc::SIGTERM, set_handler(libc::SIGTERM, forward))];
+        for &signal in &TERMINAL_SIGNALS {
+            previous.push((signal, set_handler(signal, terminal_handler)));
+        }
+
+        Forwarding {
+            previous: previous,
+        }
+    }
+
+    impl Drop for Forwarding {
+        fn drop(&mut self) {
+            for &(signal, ref action) in &self.previous {
+                unsafe { libc::sigaction(signal, action, ptr::null_mut()) };
+            }
+
+            CHILD_PID.store(0, Ordering::SeqCst);
+            PENDING_SIGNAL.store(0, Ordering::SeqCst);
+        }
+    }
+
+    /// Installs a handler, returning the previous action.
+    fn set_handler(signal: libc::c_int, handler: libc::sighandler_t) -> libc::sigaction {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handler;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            let mut previous: libc::sigaction = mem::zeroed();
+            libc::sigaction(signal, &action, &mut previous);
+
+            previous
+        }
+    }
+
+    /// Whether stscli's process group is the foreground one of the terminal it was started from.
+    fn in_foreground_process_group() -> bool {
+        unsafe {
+            let group = libc::getpgrp();
+
+            [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO].iter()
+                .any(|&fd| libc::isatty(fd) == 1 && libc::tcgetpgrp(fd) == group)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    /// Console signals already reach the child on other platforms.
+    pub struct Forwarding;
+
+    impl Forwarding {
+        pub fn set_child(&self, _pid: u32) {}
+    }
+
+    pub fn forward() -> Forwarding {
+        Forwarding
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    #[test]
+    fn signals_are_passed_on_including_those_before_the_child_starts() {
+        use libc;
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::Command;
+        use super::*;
+
+        // one test, since the handlers are shared by the whole process
+        let forwarding = forward();
+        unsafe { libc::raise(libc::SIGTERM) };
+
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        forwarding.set_child(child.id());
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        forwarding.set_child(child.id());
+        unsafe { libc::raise(libc::SIGTERM) };
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGTERM));
+    }
+}
@@ -0,0 +1,247 @@
+use rusoto_core::param::{Params, ServiceParams};
+use result::*;
+
+pub const MAX_TAGS: usize = 50;
+pub const MAX_TAG_KEY_LENGTH: usize = 128;
+pub const MAX_TAG_VALUE_LENGTH: usize = 256;
+
+/// Session tags and source identity for attribute based access control.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionTags {
+    pub tags: Vec<(String, String)>,
+    /// Keys of tags that are passed on to sessions for roles assumed using this one.
+    pub transitive_tag_keys: Vec<String>,
+    pub source_identity: Option<String>,
+}
+
+impl SessionTags {
+    pub fn new(tags: Vec<(String, String)>, transitive_tag_keys: Vec<String>, source_identity: Option<String>) -> Result<SessionTags> {
+        let session_tags = SessionTags {
+            tags: tags,
+            transitive_tag_keys: transitive_tag_keys,
+            source_identity: source_identity,
+        };
+
+        try!(session_tags.validate());
+
+        Ok(session_tags)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.transitive_tag_keys.is_empty() && self.source_identity.is_none()
+    }
+
+    /// Combines these tags with ones that take precedence, such as those given on the command line.
+    pub fn merge(&self, overrides: &SessionTags) -> Result<SessionTags> {
+        let mut tags: Vec<(String, String)> = self.tags.iter()
+            .filter(|tag| !overrides.tags.iter().any(|o| o.0.to_lowercase() == tag.0.to_lowercase()))
+            .cloned()
+            .collect();
+        tags.extend(overrides.tags.iter().cloned());
+
+        let mut transitive_tag_keys = self.transitive_tag_keys.clone();
+        for key in &overrides.transitive_tag_keys {
+            if !transitive_tag_keys.iter().any(|k| k.to_lowercase() == key.to_lowercase()) {
+                transitive_tag_keys.push(key.clone());
+            }
+        }
+
+        SessionTags::new(tags, transitive_tag_keys, overrides.source_identity.clone().or(self.source_identity.clone()))
+    }
+
+    /// Checks the rules STS applies to tags and source identities.
+    fn validate(&self) -> Result<()> {
+        if self.tags.len() > MAX_TAGS {
+            return Err(StsCliError::Error(format!("too many session tags: STS allows at most {}", MAX_TAGS)));
+        }
+
+        for (i, tag) in self.tags.iter().enumerate() {
+            let (ref key, ref value) = *tag;
+            try!(validate_tag_text("key", key, 1, MAX_TAG_KEY_LENGTH));
+            try!(validate_tag_text("value", value, 0, MAX_TAG_VALUE_LENGTH));
+
+            if key.to_lowercase().starts_with("aws:") {
+                return Err(StsCliError::Error(format!("tag key {} can't start with aws:", key)));
+            }
+
+            // keys are case insensitive
+            if self.tags[..i].iter().any(|other| other.0.to_lowercase() == key.to_lowercase()) {
+                return Err(StsCliError::Error(format!("tag key {} is given more than once", key)));
+            }
+        }
+
+        if self.transitive_tag_keys.len() > MAX_TAGS {
+            return Err(StsCliError::Error(format!("too many transitive tag keys: STS allows at most {}", MAX_TAGS)));
+        }
+
+        for key in &self.transitive_tag_keys {
+            if !self.tags.iter().any(|tag| tag.0.to_lowercase() == key.to_lowercase()) {
+                return Err(StsCliError::Error(format!("transitive tag key {} must also be given as a tag", key)));
+            }
+        }
+
+        if let Some(ref source_identity) = self.source_identity {
+            if source_identity.to_lowercase().starts_with("aws:") {
+                return Err(StsCliError::Error(format!("source identity {} can't start with aws:", source_identity)));
+            }
+
+            let valid_chars = source_identity.chars().all(|c| c.is_alphanumeric() || "_+=,.@-".contains(c));
+
+            if source_identity.len() < 2 || source_identity.len() > 64 || !valid_chars {
+                return Err(StsCliError::Error(format!("source identity {} must be 2 to 64 letters, digits or any of _+=,.@-", source_identity)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The `Tags`, `TransitiveTagKeys` and `SourceIdentity` request parameters, which the STS client doesn't support.
+    pub fn params(&self) -> Params {
+        let mut params = Params::new();
+
+        for (i, tag) in self.tags.iter().enumerate() {
+            let (ref key, ref value) = *tag;
+            params.put(&format!("Tags.member.{}.Key", i + 1), key);
+            params.put(&format!("Tags.member.{}.Value", i + 1), value);
+        }
+
+        for (i, key) in self.transitive_tag_keys.iter().enumerate() {
+            params.put(&format!("TransitiveTagKeys.member.{}", i + 1), key);
+        }
+
+        if let Some(ref source_identity) = self.source_identity {
+            params.put("SourceIdentity", source_identity);
+        }
+
+        params
+    }
+}
+
+/// Parses a tag written as `key=value`.
+pub fn parse_tag(s: &str) -> Result<(String, String)> {
+    match s.find('=') {
+        Some(i) => Ok((s[..i].trim().to_owned(), s[i + 1..].trim().to_owned())),
+        None => Err(StsCliError::Error(format!("tag {} should be written as key=value", s))),
+    }
+}
+
+/// Parses a comma separated list of tags, as used in the config file, e.g. `team=infra,project=billing`.
+pub fn parse_tag_list(s: &str) -> Result<Vec<(String, String)>> {
+    let mut tags = vec![];
+
+    for tag in s.split(',').filter(|t| !t.trim().is_empty()) {
+        tags.push(try!(parse_tag(tag)));
+    }
+
+    Ok(tags)
+}
+
+/// Parses a comma separated list of tag keys.
+pub fn parse_key_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|k| k.trim().to_owned())
+        .filter(|k| !k.is_empty())
+        .collect()
+}
+
+fn validate_tag_text(what: &str, text: &str, min: usize, max: usize) -> Result<()> {
+    let length = text.chars().count();
+
+    if length < min || length > max {
+        return Err(StsCliError::Error(format!("tag {} {} must be {} to {} characters long", what, text, min, max)));
+    }
+
+    let valid_chars = text.chars().all(|c| c.is_alphanumeric() || c.is_whitespace() || "_.:/=+-@".contains(c));
+
+    if !valid_chars {
+        return Err(StsCliError::Error(format!("tag {} {} may only contain letters, digits, spaces and any of _.:/=+-@", what, text)));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect()
+    }
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_tags_and_keys() {
+        assert_eq!(parse_tag(" team = infra ").unwrap(), ("team".to_owned(), "infra".to_owned()));
+        assert_eq!(parse_tag("expr=a=b").unwrap(), ("expr".to_owned(), "a=b".to_owned()));
+        assert_eq!(parse_tag("empty=").unwrap(), ("empty".to_owned(), "".to_owned()));
+        assert!(parse_tag("team").is_err());
+
+        assert_eq!(parse_tag_list("team=infra, project=billing,").unwrap(), tags(&[("team", "infra"), ("project", "billing")]));
+        assert_eq!(parse_tag_list("").unwrap(), vec![]);
+        assert!(parse_tag_list("team=infra,oops").is_err());
+
+        assert_eq!(parse_key_list(" team,, project "), keys(&["team", "project"]));
+    }
+
+    #[test]
+    fn validates_tags() {
+        assert!(SessionTags::new(tags(&[("team", "infra"), ("path", "a/b:c@d.e+f-g_h")]), vec![], None).is_ok());
+
+        assert!(SessionTags::new(tags(&[("", "infra")]), vec![], None).is_err());
+        assert!(SessionTags::new(tags(&[("aws:team", "infra")]), vec![], None).is_err());
+        assert!(SessionTags::new(tags(&[("team", "infra"), ("Team", "ops")]), vec![], None).is_err());
+        assert!(SessionTags::new(tags(&[("team", "in;fra")]), vec![], None).is_err());
+        assert!(SessionTags::new(tags(&[(&"k".repeat(MAX_TAG_KEY_LENGTH + 1), "v")]), vec![], None).is_err());
+        assert!(SessionTags::new(tags(&[("k", &"v".repeat(MAX_TAG_VALUE_LENGTH + 1))]), vec![], None).is_err());
+
+        let too_many: Vec<(String, String)> = (0..MAX_TAGS + 1).map(|i| (format!("k{}", i), "v".to_owned())).collect();
+        assert!(SessionTags::new(too_many, vec![], None).is_err());
+    }
+
+    #[test]
+    fn transitive_keys_must_be_tags() {
+        assert!(SessionTags::new(tags(&[("team", "infra")]), keys(&["Team"]), None).is_ok());
+        assert!(SessionTags::new(tags(&[("team", "infra")]), keys(&["project"]), None).is_err());
+    }
+
+    #[test]
+    fn validates_source_identity() {
+        assert!(SessionTags::new(vec![], vec![], Some("alice@example.com".to_owned())).is_ok());
+
+        for source_identity in &["a", "aws:alice", "alice smith", &"a".repeat(65)] {
+            assert!(SessionTags::new(vec![], vec![], Some(source_identity.to_string())).is_err(), "{:?}", source_identity);
+        }
+    }
+
+    #[test]
+    fn overrides_win_when_merging() {
+        let profile = SessionTags::new(tags(&[("team", "infra"), ("project", "billing")]), keys(&["team"]), Some("profile".to_owned())).unwrap();
+        let command_line = SessionTags::new(tags(&[("Team", "ops")]), keys(&["Team"]), None).unwrap();
+
+        let merged = profile.merge(&command_line).unwrap();
+
+        assert_eq!(merged.tags, tags(&[("project", "billing"), ("Team", "ops")]));
+        assert_eq!(merged.transitive_tag_keys, keys(&["team"]));
+        assert_eq!(merged.source_identity, Some("profile".to_owned()));
+    }
+
+    #[test]
+    fn params_are_numbered_from_one() {
+        let params = SessionTags::new(tags(&[("team", "infra"), ("project", "billing")]), keys(&["project"]), Some("alice".to_owned()))
+            .unwrap()
+            .params();
+
+        let get = |k: &str| params.get(k).cloned().and_then(|v| v);
+        assert_eq!(get("Tags.member.1.Key"), Some("team".to_owned()));
+        assert_eq!(get("Tags.member.2.Value"), Some("billing".to_owned()));
+        assert_eq!(get("TransitiveTagKeys.member.1"), Some("project".to_owned()));
+        assert_eq!(get("SourceIdentity"), Some("alice".to_owned()));
+        assert_eq!(params.len(), 6);
+
+        assert!(SessionTags::default().params().is_empty());
+        assert!(SessionTags::default().is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use aws_config::ConfigProfile;
+use config::Config;
+use result::*;
+
+/// A token from an OpenID Connect provider, such as a CI system, and the role to exchange it for.
+#[derive(Debug, Clone)]
+pub struct WebIdentity {
+    pub token_file: PathBuf,
+    pub role_arn: String,
+    pub role_session_name: String,
+}
+
+impl WebIdentity {
+    /// Web identity federation is used by the assume-web-identity subcommand,
+    /// or when a token file is given on the command line or in the profile.
+    pub fn is_selected(config: &Config, profile_config: Option<&ConfigProfile>) -> bool {
+        config.web_identity
+            || config.web_identity_token_file.is_some()
+            || profile_config.map(|p| p.web_identity_token_file.is_some()).unwrap_or(false)
+    }
+
+    /// Command line options take precedence over the profile, and the profile over the
+    /// `AWS_WEB_IDENTITY_TOKEN_FILE`, `AWS_ROLE_ARN` and `AWS_ROLE_SESSION_NAME` environment variables.
+    pub fn resolve(config: &Config, profile_config: Option<&ConfigProfile>) -> Result<WebIdentity> {
+        let token_file = try!(config.web_identity_token_file.clone()
+            .or_else(|| profile_config.and_then(|p| p.web_identity_token_file.clone()).map(PathBuf::from))
+            .or_else(|| env::var_os("AWS_WEB_IDENTITY_TOKEN_FILE").map(PathBuf::from))
+            .ok_or_else(|| StsCliError::Error("no web identity token file: use --web-identity-token-file, set web_identity_token_file in the profile or set AWS_WEB_IDENTITY_TOKEN_FILE".to_owned())));
+
+        let role_arn = try!(config.role.clone()
+            .or_else(|| profile_config.and_then(|p| p.role_arn.clone()))
+            .or_else(|| env::var("AWS_ROLE_ARN").ok())
+            .ok_or_else(|| StsCliError::Error("no role to assume with the web identity token: use --role, set role_arn in the profile or set AWS_ROLE_ARN".to_owned())));
+
+        let role_session_name = config.name.clone()
+            .or_else(|| profile_config.and_then(|p| p.role_session_name.clone()))
+            .or_else(|| env::var("AWS_ROLE_SESSION_NAME").ok())
+            .unwrap_or("stscli".to_owned());
+
+        Ok(WebIdentity {
+            token_file: token_file,
+            role_arn: role_arn,
+            role_session_name: role_session_name,
+        })
+    }
+
+    /// Reads the token afresh, since providers rotate the file before the token expires.
+    pub fn read_token(&self) -> Result<String> {
+        let mut token = String::new();
+
+        let mut file = try!(File::open(&self.token_file)
+            .map_err(|e| StsCliError::Error(format!("can't open web identity token file {}: {}", self.token_file.display(), e))));
+        try!(file.read_to_string(&mut token));
+
+        let token = token.trim().to_owned();
+
+        if token.is_empty() {
+            return Err(StsCliError::Error(format!("web identity token file {} is empty", self.token_file.display())));
+        }
+
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use aws_config::ConfigProfile;
+    use config::Config;
+    use files;
+    use super::*;
+
+    fn token_file(name: &str, contents: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("stscli-web-identity-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join(name);
+        files::write_private_file_atomic(&path, contents.as_bytes()).unwrap();
+        path
+    }
+
+    fn profile() -> ConfigProfile {
+        let mut profile = ConfigProfile::new("ci");
+        profile.role_arn = Some("arn:aws:iam::111111111111:role/profile".to_owned());
+        profile.role_session_name = Some("profile-session".to_owned());
+        profile.web_identity_token_file = Some("/profile/token".to_owned());
+        profile
+    }
+
+    #[test]
+    fn is_selected_by_subcommand_option_or_profile() {
+        assert!(!WebIdentity::is_selected(&Config::default(), None));
+        assert!(!WebIdentity::is_selected(&Config::default(), Some(&ConfigProfile::new("keys"))));
+        assert!(WebIdentity::is_selected(&Config { web_identity: true, ..Default::default() }, None));
+        assert!(WebIdentity::is_selected(&Config { web_identity_token_file: Some(PathBuf::from("token")), ..Default::default() }, None));
+        assert!(WebIdentity::is_selected(&Config::default(), Some(&profile())));
+    }
+
+    // the only test that reads the AWS_* variables, so setting them can't affect the others
+    #[test]
+    fn options_win_over_the_profile_and_the_profile_over_the_environment() {
+        env::set_var("AWS_WEB_IDENTITY_TOKEN_FILE", "/env/token");
+        env::set_var("AWS_ROLE_ARN", "arn:aws:iam::111111111111:role/env");
+        env::set_var("AWS_ROLE_SESSION_NAME", "env-session");
+
+        let from_env = WebIdentity::resolve(&Config::default(), None).unwrap();
+        assert_eq!(from_env.token_file, Path::new("/env/token"));
+        assert_eq!(from_env.role_arn, "arn:aws:iam::111111111111:role/env");
+        assert_eq!(from_env.role_session_name, "env-session");
+
+        let from_profile = WebIdentity::resolve(&Config::default(), Some(&profile())).unwrap();
+        assert_eq!(from_profile.token_file, Path::new("/profile/token"));
+        assert_eq!(from_profile.role_arn, "arn:aws:iam::111111111111:role/profile");
+        assert_eq!(from_profile.role_session_name, "profile-session");
+
+        let options = Config {
+            web_identity_token_file: Some(PathBuf::from("/options/token")),
+            role: Some("arn:aws:iam::111111111111:role/options".to_owned()),
+            name: Some("options-session".to_owned()),
+            ..Default::default()
+        };
+        let from_options = WebIdentity::resolve(&options, Some(&profile())).unwrap();
+        assert_eq!(from_options.token_file, Path::new("/options/token"));
+        assert_eq!(from_options.role_arn, "arn:aws:iam::111111111111:role/options");
+        assert_eq!(from_options.role_session_name, "options-session");
+
+        env::remove_var("AWS_WEB_IDENTITY_TOKEN_FILE");
+        env::remove_var("AWS_ROLE_ARN");
+        env::remove_var("AWS_ROLE_SESSION_NAME");
+
+        assert!(WebIdentity::resolve(&Config::default(), None).is_err());
+        assert!(WebIdentity::resolve(&Config { web_identity_token_file: Some(PathBuf::from("token")), ..Default::default() }, None).is_err());
+        assert_eq!(WebIdentity::resolve(&Config::default(), Some(&profile())).unwrap().role_session_name, "profile-session");
+    }
+
+    fn identity(token_file: PathBuf) -> WebIdentity {
+        WebIdentity {
+            token_file: token_file,
+            role_arn: "arn:aws:iam::111111111111:role/ci".to_owned(),
+            role_session_name: "stscli".to_owned(),
+        }
+    }
+
+    #[test]
+    fn reads_the_token_trimmed() {
+        assert_eq!(identity(token_file("token", "eyJhbGciOi.eyJzdWIi.c2lnbmF0dXJl\n")).read_token().unwrap(), "eyJhbGciOi.eyJzdWIi.c2lnbmF0dXJl");
+    }
+
+    #[test]
+    fn rejects_missing_or_empty_tokens() {
+        assert!(identity(token_file("empty", " \n")).read_token().is_err());
+        assert!(identity(PathBuf::from("/nonexistent/token")).read_token().is_err());
+    }
+}